@@ -0,0 +1,4261 @@
+//! An in memory cache of parquet files. Queries can be served from files held
+//! here without needing to go out to the object store that the persister
+//! writes to.
+
+use crate::catalog::TIME_COLUMN_NAME;
+use crate::persister::{serialize_to_parquet, ParquetCompression};
+use crate::ParquetFile;
+use bytes::Bytes;
+use datafusion::common::DataFusionError;
+use datafusion::execution::memory_pool::{MemoryConsumer, MemoryPool, MemoryReservation};
+use datafusion::physical_plan::SendableRecordBatchStream;
+use futures_util::future::{BoxFuture, Shared};
+use futures_util::{FutureExt, Stream, TryStreamExt};
+use iox_time::{SystemProvider, Time, TimeProvider};
+use object_store::memory::InMemory;
+use object_store::path::Path as ObjPath;
+use object_store::ObjectStore;
+use observability_deps::tracing::{error, warn};
+use parking_lot::{Mutex, RwLock};
+use parquet::arrow::async_reader::{AsyncFileReader, ParquetObjectReader};
+use parquet::file::metadata::ParquetMetaData;
+use parquet::file::statistics::Statistics;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use thiserror::Error;
+use tokio::sync::{broadcast, oneshot};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("persister error: {0}")]
+    Persister(#[from] crate::persister::Error),
+
+    #[error("object_store error: {0}")]
+    ObjectStore(#[from] object_store::Error),
+
+    #[error("datafusion error: {0}")]
+    DataFusion(#[from] DataFusionError),
+
+    #[error("parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+
+    #[error("the cached file at {path} doesn't match the checksum recorded when it was persisted")]
+    Corrupted { path: String },
+
+    #[error("shared persist of the cached file failed: {0}")]
+    SharedPersist(Arc<Error>),
+
+    #[error("expected version {expected} of the cached file but found version {actual}")]
+    VersionConflict { expected: u64, actual: u64 },
+
+    #[error("the cached file at {path} belongs to table {table_name} in database {db_name}")]
+    PathInOtherTable {
+        path: String,
+        db_name: String,
+        table_name: String,
+    },
+
+    #[error("the in flight persist of the cached file at {path} failed or was cancelled")]
+    InFlightPersistFailed { path: String },
+
+    #[error("invalid time range for the cached file: min_time {min_time} > max_time {max_time}")]
+    InvalidTimeRange { min_time: i64, max_time: i64 },
+
+    #[error("the file is {size_bytes} bytes, over the cache's limit of {max_file_bytes} bytes")]
+    FileTooLarge {
+        size_bytes: u64,
+        max_file_bytes: u64,
+    },
+
+    #[error("failed to delete {} cached files for database {db_name}", .errors.len())]
+    ClearDatabase {
+        db_name: String,
+        errors: Vec<object_store::Error>,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The default limit on the total size of the files held in the cache
+pub const DEFAULT_MAX_SIZE_BYTES: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+/// The number of [`CacheEvent`]s buffered for each subscriber. A subscriber
+/// that falls further behind than this misses the oldest events.
+const EVENT_CAPACITY: usize = 1024;
+
+/// What the cache does with a persisted file that is over its file size limit
+#[derive(Debug, Clone, Default)]
+pub enum OversizedFilePolicy {
+    /// Fail the persist with [`Error::FileTooLarge`] without storing the file
+    #[default]
+    Reject,
+    /// Store the file in the given object store, e.g. the durable store
+    /// behind the cache, rather than the cache's own object store, and don't
+    /// keep it in the cache, so that it doesn't count towards the cache's
+    /// size or push other files out. This must not be the cache's own
+    /// object store.
+    StoreUncached(Arc<dyn ObjectStore>),
+}
+
+/// How the paths of files persisted to the cache without an explicit path
+/// are generated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathScheme {
+    /// A flat `{db_name}-{table_name}-{uuid}` path
+    #[default]
+    DbTableUuid,
+    /// A `{db_name}/{table_name}/{uuid}.parquet` path, so that all of the
+    /// files for a database or table can be listed by prefix in the object
+    /// store
+    Hierarchical,
+}
+
+impl PathScheme {
+    /// The path of a new file in the given database and table
+    pub fn path(&self, db_name: &str, table_name: &str, id: uuid::Uuid) -> ObjPath {
+        match self {
+            Self::DbTableUuid => ObjPath::from(format!("{db_name}-{table_name}-{id}")),
+            Self::Hierarchical => {
+                ObjPath::from_iter([db_name, table_name, format!("{id}.parquet").as_str()])
+            }
+        }
+    }
+}
+
+/// The metadata of every file in the cache
+#[derive(Debug, Default)]
+struct MetaData {
+    /// Cached files keyed by database name, then table name, and then the
+    /// path of the file in the cache's object store
+    files: HashMap<String, HashMap<String, HashMap<String, CachedFile>>>,
+    /// The database and table name of each cached file keyed by its path, so
+    /// that a file can be found from its path alone
+    locations: HashMap<String, (String, String)>,
+    /// When a file was last persisted to each table, keyed by database name
+    /// and then table name. Tables are kept here after their files are gone.
+    last_persist: HashMap<String, HashMap<String, Time>>,
+    /// The version of the last file persisted to each path. Paths are kept
+    /// here after their files are gone, so that a path's version never goes
+    /// backwards and a writer holding a stale version can't succeed after
+    /// the file is removed and persisted again.
+    versions: HashMap<String, u64>,
+}
+
+impl MetaData {
+    /// Get the cached file at `path`
+    fn get(&self, path: &str) -> Option<&CachedFile> {
+        let (db_name, table_name) = self.locations.get(path)?;
+        self.files.get(db_name)?.get(table_name)?.get(path)
+    }
+
+    /// The version of the last file persisted to `path`, or 0 if one never
+    /// has been
+    fn version_of(&self, path: &str) -> u64 {
+        self.versions.get(path).copied().unwrap_or_default()
+    }
+
+    /// The version of the last file persisted to `path` for use by the given
+    /// table, or an error if the file cached at `path` belongs to another
+    /// table
+    fn table_version_of(&self, db_name: &str, table_name: &str, path: &str) -> Result<u64> {
+        match self.locations.get(path) {
+            Some((db, table)) if db != db_name || table != table_name => {
+                Err(Error::PathInOtherTable {
+                    path: path.to_string(),
+                    db_name: db.clone(),
+                    table_name: table.clone(),
+                })
+            }
+            _ => Ok(self.version_of(path)),
+        }
+    }
+
+    fn insert(&mut self, db_name: &str, table_name: &str, cached: CachedFile) {
+        let path = cached.file.path.clone();
+        self.versions.insert(path.clone(), cached.version);
+        self.locations
+            .insert(path.clone(), (db_name.into(), table_name.into()));
+        self.files
+            .entry(db_name.into())
+            .or_default()
+            .entry(table_name.into())
+            .or_default()
+            .insert(path, cached);
+    }
+
+    /// Remove the file at `path`, dropping the table and database entries if
+    /// they are left empty
+    fn remove(&mut self, path: &str) -> Option<CachedFile> {
+        let (db_name, table_name) = self.locations.remove(path)?;
+        let tables = self.files.get_mut(&db_name)?;
+        let files = tables.get_mut(&table_name)?;
+        let removed = files.remove(path);
+
+        if files.is_empty() {
+            tables.remove(&table_name);
+        }
+        if tables.is_empty() {
+            self.files.remove(&db_name);
+        }
+
+        removed
+    }
+
+    /// Note that a file was persisted to the given table at `time`
+    fn record_persist(&mut self, db_name: &str, table_name: &str, time: Time) {
+        self.last_persist
+            .entry(db_name.to_string())
+            .or_default()
+            .insert(table_name.to_string(), time);
+    }
+
+    /// Remove every file in the given database
+    fn remove_database(&mut self, db_name: &str) -> Vec<CachedFile> {
+        self.last_persist.remove(db_name);
+        let Some(tables) = self.files.remove(db_name) else {
+            return vec![];
+        };
+
+        let removed: Vec<CachedFile> = tables
+            .into_values()
+            .flat_map(|files| files.into_values())
+            .collect();
+        for cached in &removed {
+            self.locations.remove(&cached.file.path);
+        }
+
+        removed
+    }
+
+    /// The path of the file of the given table covering the oldest time
+    /// range, other than the file at `keep`, if the table holds more than
+    /// `max_rows` rows
+    fn over_row_budget_candidate(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        max_rows: u64,
+        keep: &str,
+    ) -> Option<String> {
+        let files = self.files.get(db_name)?.get(table_name)?;
+        let rows: u64 = files.values().map(|cached| cached.file.row_count).sum();
+        if rows <= max_rows {
+            return None;
+        }
+
+        files
+            .values()
+            .filter(|cached| cached.file.path != keep)
+            .min_by_key(|cached| cached.file.max_time)
+            .map(|cached| cached.file.path.clone())
+    }
+
+    /// The path of the file that should be evicted next
+    fn eviction_candidate(&self) -> Option<String> {
+        self.files
+            .values()
+            .flat_map(|tables| tables.values())
+            .flat_map(|files| files.values())
+            .min_by_key(|cached| cached.last_access.load(Ordering::SeqCst))
+            .map(|cached| cached.file.path.clone())
+    }
+}
+
+/// A file held in the cache along with the bookkeeping the cache keeps for it
+#[derive(Debug)]
+struct CachedFile {
+    file: ParquetFile,
+    /// Incremented every time a file is persisted to this path, starting at 1
+    /// and carrying on from where it left off if the path's last file was
+    /// removed or evicted
+    version: u64,
+    /// When the file was last inserted, loaded or listed relative to the
+    /// other files in the cache, with the least recently used file having the
+    /// lowest value
+    last_access: AtomicU64,
+    /// The CRC32 of the file's bytes, if it was recorded when the file was
+    /// persisted
+    checksum: Option<u32>,
+}
+
+/// A file evicted from the cache's metadata
+#[derive(Debug)]
+struct Evicted {
+    file: ParquetFile,
+    /// Whether the file's object is copied to the cache's spill store before
+    /// it's deleted, as the files evicted by their table's row budget are
+    spill: bool,
+}
+
+/// A file that has been put in an object store but isn't recorded in the
+/// cache's metadata yet
+#[derive(Debug)]
+struct WrittenFile {
+    file: ParquetFile,
+    checksum: Option<u32>,
+    /// Whether the file should be recorded in the cache at all, which it
+    /// isn't if it's over the cache's file size limit. Files that aren't
+    /// cached are put in the store given by the cache's
+    /// [`OversizedFilePolicy`] rather than the cache's own store.
+    cached: bool,
+}
+
+/// A summary of what the cache holds and how it has been used
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of files in the cache
+    pub file_count: usize,
+    /// The total size of the files in the cache
+    pub size_bytes: u64,
+    /// The number of files in the cache for each database
+    pub database_file_counts: HashMap<String, usize>,
+    /// How many loads found the file in the cache
+    pub hits: u64,
+    /// How many loads were for a file that isn't in the cache
+    pub misses: u64,
+}
+
+/// The combined metadata of every cached file for a table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableCacheSummary {
+    /// The number of files cached for the table
+    pub file_count: usize,
+    /// The number of rows across all of the table's files
+    pub total_rows: u64,
+    /// The earliest time of any of the table's files
+    pub min_time: i64,
+    /// The latest time of any of the table's files
+    pub max_time: i64,
+    /// The total size of the table's files
+    pub total_bytes: u64,
+}
+
+/// An owned copy of the metadata of every file in the cache, taken at a
+/// single point in time, that can be queried without taking any locks
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheSnapshot {
+    /// The files in the cache keyed by database name and then table name
+    files: HashMap<String, HashMap<String, Vec<ParquetFile>>>,
+}
+
+impl CacheSnapshot {
+    /// The names of every database with files in the snapshot, in sorted
+    /// order
+    pub fn databases(&self) -> Vec<&str> {
+        let mut databases: Vec<&str> = self.files.keys().map(String::as_str).collect();
+        databases.sort();
+        databases
+    }
+
+    /// The names of every table in the given database with files in the
+    /// snapshot, in sorted order
+    pub fn tables(&self, database_name: &str) -> Vec<&str> {
+        let mut tables: Vec<&str> = self
+            .files
+            .get(database_name)
+            .map(|tables| tables.keys().map(String::as_str).collect())
+            .unwrap_or_default();
+        tables.sort();
+        tables
+    }
+
+    /// The files of the given table in the snapshot
+    pub fn parquet_files(&self, database_name: &str, table_name: &str) -> &[ParquetFile] {
+        self.files
+            .get(database_name)
+            .and_then(|tables| tables.get(table_name))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// The files of the given table in the snapshot that overlap the
+    /// inclusive time range
+    pub fn parquet_files_in_range(
+        &self,
+        database_name: &str,
+        table_name: &str,
+        min_time: i64,
+        max_time: i64,
+    ) -> Vec<&ParquetFile> {
+        self.parquet_files(database_name, table_name)
+            .iter()
+            .filter(|file| file.min_time <= max_time && file.max_time >= min_time)
+            .collect()
+    }
+}
+
+/// Generates the UUIDs used in the paths of files persisted to the cache
+/// without an explicit path
+pub type UuidGenerator = Arc<dyn Fn() -> uuid::Uuid + Send + Sync>;
+
+/// The cache's [`UuidGenerator`]
+struct NewUuid(UuidGenerator);
+
+impl Default for NewUuid {
+    fn default() -> Self {
+        Self(Arc::new(uuid::Uuid::new_v4))
+    }
+}
+
+impl fmt::Debug for NewUuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<uuid generator>")
+    }
+}
+
+/// A change to the files held in the cache, sent to every receiver returned
+/// by [`ParquetCache::subscribe`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheEvent {
+    /// A file was persisted to the cache, or found in its object store by
+    /// [`ParquetCache::load_metadata_from_store`]
+    Inserted(ParquetFile),
+    /// A file was evicted from the cache to make room for others
+    Evicted(ParquetFile),
+    /// The file at the given path was removed from the cache
+    Removed(String),
+}
+
+/// Called with the metadata of every file evicted from the cache
+pub type EvictionCallback = Arc<dyn Fn(&ParquetFile) + Send + Sync>;
+
+/// The cache's [`EvictionCallback`], if it has one
+#[derive(Default)]
+struct OnEvict(Option<EvictionCallback>);
+
+impl fmt::Debug for OnEvict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "Some(<callback>)"),
+            None => write!(f, "None"),
+        }
+    }
+}
+
+type InFlightPersist = Shared<BoxFuture<'static, Result<ObjPath, Arc<Error>>>>;
+
+/// Persists started by [`ParquetCache::get_or_persist`] or
+/// [`ParquetCache::persist_parquet_file`] that have not yet finished
+#[derive(Default)]
+struct InFlight {
+    /// The persists keyed by the path they are persisting to
+    persists: HashMap<String, InFlightPersist>,
+    /// The ids of the persists started by [`ParquetCache::get_or_persist`],
+    /// which only make progress while something is waiting on them, keyed by
+    /// path
+    waiter_driven: HashMap<String, u64>,
+    next_id: u64,
+}
+
+impl fmt::Debug for InFlight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.persists.keys()).finish()
+    }
+}
+
+/// Marks a [`ParquetCache::persist_parquet_file`] as in flight for as long as
+/// it is held. Dropping it without calling [`InFlightGuard::finish`], because
+/// the persist failed or was cancelled, fails the persist for anyone waiting
+/// on it.
+struct InFlightGuard<'a> {
+    in_flight: &'a Mutex<InFlight>,
+    path: ObjPath,
+    tx: Option<oneshot::Sender<ObjPath>>,
+}
+
+impl<'a> InFlightGuard<'a> {
+    /// Register a persist to `path`. The caller must hold the `in_flight`
+    /// lock and have checked there isn't already a persist to `path`.
+    fn register(in_flight: &'a Mutex<InFlight>, locked: &mut InFlight, path: &ObjPath) -> Self {
+        let (tx, rx) = oneshot::channel();
+        let failed_path = path.to_string();
+        let persist = rx
+            .map(move |result| {
+                result.map_err(|_| Arc::new(Error::InFlightPersistFailed { path: failed_path }))
+            })
+            .boxed()
+            .shared();
+        locked.persists.insert(path.to_string(), persist);
+
+        Self {
+            in_flight,
+            path: path.clone(),
+            tx: Some(tx),
+        }
+    }
+
+    /// Mark the persist as successful. This must only be called once the
+    /// file has been recorded in the cache's metadata.
+    fn finish(mut self) {
+        self.in_flight.lock().persists.remove(self.path.as_ref());
+        if let Some(tx) = self.tx.take() {
+            // Nobody waiting on the persist isn't an error
+            let _ = tx.send(self.path.clone());
+        }
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if self.tx.is_some() {
+            self.in_flight.lock().persists.remove(self.path.as_ref());
+        }
+    }
+}
+
+/// Held by a caller of [`ParquetCache::get_or_persist`] while it waits on a
+/// persist started by one. Such a persist only makes progress while
+/// something waits on it, so should the last caller waiting on it stop,
+/// e.g. because it was cancelled, the persist is dropped from the in flight
+/// map. Otherwise it would hold on to the cache forever, or run a cancelled
+/// caller's `produce` for whoever next asks for the same key.
+struct WaiterGuard<'a> {
+    in_flight: &'a Mutex<InFlight>,
+    path: &'a str,
+    id: u64,
+    persist: Option<InFlightPersist>,
+}
+
+impl WaiterGuard<'_> {
+    /// Wait for the persist to finish
+    async fn wait(mut self) -> Result<ObjPath, Arc<Error>> {
+        let persist = self.persist.as_mut().expect("only taken once finished");
+        let result = persist.await;
+        self.persist = None;
+        result
+    }
+}
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        let Some(persist) = self.persist.take() else {
+            return;
+        };
+
+        let mut in_flight = self.in_flight.lock();
+        // Handles to the persist are only cloned under the in flight lock, so
+        // nothing can start waiting on it while the lock is held. If this is
+        // the last waiter the only other handle is the in flight map's.
+        let abandoned = in_flight.waiter_driven.get(self.path) == Some(&self.id)
+            && persist.strong_count() == Some(2);
+        let removed = if abandoned {
+            in_flight.waiter_driven.remove(self.path);
+            in_flight.persists.remove(self.path)
+        } else {
+            None
+        };
+        drop(in_flight);
+
+        // Dropping the persist may drop the last handle to the cache, which
+        // must not happen while its lock is held
+        drop(persist);
+        drop(removed);
+    }
+}
+
+/// Wraps the memory pool given to the cache so that the memory reserved by
+/// the cache's own parquet serialization can be told apart from everything
+/// else using the pool
+#[derive(Debug)]
+struct CacheMemoryPool {
+    inner: Arc<dyn MemoryPool>,
+    reserved: AtomicUsize,
+}
+
+impl MemoryPool for CacheMemoryPool {
+    fn register(&self, consumer: &MemoryConsumer) {
+        self.inner.register(consumer)
+    }
+
+    fn unregister(&self, consumer: &MemoryConsumer) {
+        self.inner.unregister(consumer)
+    }
+
+    fn grow(&self, reservation: &MemoryReservation, additional: usize) {
+        self.inner.grow(reservation, additional);
+        self.reserved.fetch_add(additional, Ordering::SeqCst);
+    }
+
+    fn shrink(&self, reservation: &MemoryReservation, shrink: usize) {
+        self.inner.shrink(reservation, shrink);
+        self.reserved.fetch_sub(shrink, Ordering::SeqCst);
+    }
+
+    fn try_grow(
+        &self,
+        reservation: &MemoryReservation,
+        additional: usize,
+    ) -> datafusion::common::Result<()> {
+        self.inner.try_grow(reservation, additional)?;
+        self.reserved.fetch_add(additional, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn reserved(&self) -> usize {
+        self.reserved.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParquetCache {
+    object_store: Arc<dyn ObjectStore>,
+    meta_data: RwLock<MetaData>,
+    mem_pool: Arc<CacheMemoryPool>,
+    in_flight: Mutex<InFlight>,
+    path_scheme: PathScheme,
+    new_uuid: NewUuid,
+    /// Whether a checksum of each persisted file is recorded and checked
+    /// when the file is loaded
+    verify_checksums: bool,
+    /// The compression used for files of tables without their own
+    compression: ParquetCompression,
+    /// The compression used for the files of particular tables, keyed by
+    /// database and then table name
+    table_compression: RwLock<HashMap<String, HashMap<String, ParquetCompression>>>,
+    /// The most rows to keep cached for particular tables, keyed by database
+    /// and then table name
+    table_row_budgets: RwLock<HashMap<String, HashMap<String, u64>>>,
+    /// Where the files evicted by a table's row budget are moved to, if
+    /// anywhere
+    spill_store: Option<Arc<dyn ObjectStore>>,
+    time_provider: Arc<dyn TimeProvider>,
+    on_evict: OnEvict,
+    /// The sender for [`CacheEvent`]s, only created once something
+    /// subscribes to them
+    events: Mutex<Option<broadcast::Sender<CacheEvent>>>,
+    /// The limit on the total size of the cached files. The least recently
+    /// used files are evicted first to keep the cache under it.
+    max_size_bytes: u64,
+    /// The size limit for a single file, if there is one
+    max_file_bytes: Option<u64>,
+    oversized_file_policy: OversizedFilePolicy,
+    /// The total size of the cached files. Only updated while holding the
+    /// write lock on `meta_data`.
+    size_bytes: AtomicU64,
+    /// Logical clock used to order files by when they were last used
+    access_clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ParquetCache {
+    /// Create a new `ParquetCache` backed by an in memory object store that
+    /// holds at most `max_size_bytes` worth of parquet files
+    pub fn new(mem_pool: &Arc<dyn MemoryPool>, max_size_bytes: u64) -> Self {
+        Self::with_object_store(Arc::new(InMemory::new()), mem_pool, max_size_bytes)
+    }
+
+    /// Create a new `ParquetCache` that stores its files in the given object
+    /// store, e.g. local disk or S3, rather than in memory
+    pub fn with_object_store(
+        object_store: Arc<dyn ObjectStore>,
+        mem_pool: &Arc<dyn MemoryPool>,
+        max_size_bytes: u64,
+    ) -> Self {
+        Self {
+            object_store,
+            meta_data: RwLock::new(MetaData::default()),
+            mem_pool: Arc::new(CacheMemoryPool {
+                inner: Arc::clone(mem_pool),
+                reserved: AtomicUsize::new(0),
+            }),
+            in_flight: Mutex::new(InFlight::default()),
+            path_scheme: PathScheme::default(),
+            new_uuid: NewUuid::default(),
+            verify_checksums: false,
+            compression: ParquetCompression::default(),
+            table_compression: RwLock::new(HashMap::new()),
+            table_row_budgets: RwLock::new(HashMap::new()),
+            spill_store: None,
+            time_provider: Arc::new(SystemProvider::new()),
+            on_evict: OnEvict::default(),
+            events: Mutex::new(None),
+            max_size_bytes,
+            max_file_bytes: None,
+            oversized_file_policy: OversizedFilePolicy::default(),
+            size_bytes: AtomicU64::new(0),
+            access_clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Use `path_scheme` to generate the paths of files persisted without an
+    /// explicit path
+    pub fn with_path_scheme(mut self, path_scheme: PathScheme) -> Self {
+        self.path_scheme = path_scheme;
+        self
+    }
+
+    /// Use `new_uuid` rather than random v4 UUIDs in the paths of files
+    /// persisted without an explicit path, e.g. to get predictable paths in
+    /// tests
+    pub fn with_uuid_generator(mut self, new_uuid: UuidGenerator) -> Self {
+        self.new_uuid = NewUuid(new_uuid);
+        self
+    }
+
+    /// Record a checksum of every file persisted to the cache and check that
+    /// the file's bytes still match it whenever it's loaded with
+    /// [`ParquetCache::load_parquet_file`], to catch corruption in the object
+    /// store
+    pub fn with_checksums(mut self) -> Self {
+        self.verify_checksums = true;
+        self
+    }
+
+    /// Compress files persisted to the cache with `compression` unless their
+    /// table has its own set with [`ParquetCache::set_table_compression`]
+    pub fn with_compression(mut self, compression: ParquetCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Compress files persisted to the cache for the given table with
+    /// `compression` rather than the cache's default, e.g. to use ZSTD for
+    /// cold tables and SNAPPY for hot ones
+    pub fn set_table_compression(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        compression: ParquetCompression,
+    ) {
+        self.table_compression
+            .write()
+            .entry(db_name.to_string())
+            .or_default()
+            .insert(table_name.to_string(), compression);
+    }
+
+    /// The compression used for files persisted to the given table
+    pub fn table_compression(&self, db_name: &str, table_name: &str) -> ParquetCompression {
+        self.table_compression
+            .read()
+            .get(db_name)
+            .and_then(|tables| tables.get(table_name))
+            .copied()
+            .unwrap_or(self.compression)
+    }
+
+    /// Limit the size of a single file persisted to the cache to
+    /// `max_file_bytes`, so that one enormous file can't take up the whole
+    /// cache. `policy` decides what happens to files over the limit, and to
+    /// files larger than the whole cache whether or not a limit is set.
+    pub fn with_max_file_bytes(mut self, max_file_bytes: u64, policy: OversizedFilePolicy) -> Self {
+        self.max_file_bytes = Some(max_file_bytes);
+        self.oversized_file_policy = policy;
+        self
+    }
+
+    /// Move the files evicted by a table's row budget to `spill_store`, e.g.
+    /// the durable store behind the cache, rather than deleting them. Once
+    /// spilled a file is no longer the cache's, so it doesn't count towards
+    /// the cache's size and isn't removed with the cache's files. This must
+    /// not be the cache's own object store.
+    pub fn with_spill_store(mut self, spill_store: Arc<dyn ObjectStore>) -> Self {
+        self.spill_store = Some(spill_store);
+        self
+    }
+
+    /// Call `on_evict` with every file that is evicted from the cache, by
+    /// either its size limit or a table's row budget, e.g. to record where
+    /// the file went in a catalog. It's called once the cache's locks have
+    /// been released and before the file's object is deleted.
+    pub fn with_on_evict(mut self, on_evict: EvictionCallback) -> Self {
+        self.on_evict = OnEvict(Some(on_evict));
+        self
+    }
+
+    /// Subscribe to the files being inserted into, evicted from and removed
+    /// from the cache from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<CacheEvent> {
+        self.events
+            .lock()
+            .get_or_insert_with(|| broadcast::channel(EVENT_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// The sender for [`CacheEvent`]s, if anything is still subscribed to
+    /// them
+    fn event_sender(&self) -> Option<broadcast::Sender<CacheEvent>> {
+        self.events
+            .lock()
+            .as_ref()
+            .filter(|events| events.receiver_count() > 0)
+            .cloned()
+    }
+
+    /// Use `time_provider` to tell when files are persisted
+    pub fn with_time_provider(mut self, time_provider: Arc<dyn TimeProvider>) -> Self {
+        self.time_provider = time_provider;
+        self
+    }
+
+    /// Keep at most `max_rows` rows cached for the given table. When a
+    /// persist takes the table over its budget the table's files covering
+    /// the oldest time ranges are evicted until it fits, so that the most
+    /// recent data stays cached. The file being persisted is never evicted
+    /// to make room for itself.
+    ///
+    /// If the cache has a spill store, set with
+    /// [`ParquetCache::with_spill_store`], the evicted files are moved to it,
+    /// spilling the historical data out of the cache. Otherwise they're
+    /// deleted like any other evicted file.
+    pub fn set_table_row_budget(&self, db_name: &str, table_name: &str, max_rows: u64) {
+        self.table_row_budgets
+            .write()
+            .entry(db_name.to_string())
+            .or_default()
+            .insert(table_name.to_string(), max_rows);
+    }
+
+    fn table_row_budget(&self, db_name: &str, table_name: &str) -> Option<u64> {
+        self.table_row_budgets
+            .read()
+            .get(db_name)
+            .and_then(|tables| tables.get(table_name))
+            .copied()
+    }
+
+    /// The total size in bytes of all files currently in the cache
+    pub fn current_size_bytes(&self) -> u64 {
+        self.size_bytes.load(Ordering::SeqCst)
+    }
+
+    /// The number of bytes of the memory pool currently reserved by the
+    /// cache to serialize files being persisted
+    pub fn reserved_bytes(&self) -> usize {
+        self.mem_pool.reserved()
+    }
+
+    /// The number of bytes currently reserved from the memory pool given to
+    /// the cache by every user of the pool, including the cache itself
+    pub fn pool_reserved_bytes(&self) -> usize {
+        self.mem_pool.inner.reserved()
+    }
+
+    /// Get a summary of the contents of the cache and how often loads have
+    /// hit and missed it
+    pub fn stats(&self) -> CacheStats {
+        let meta_data = self.meta_data.read();
+        let database_file_counts: HashMap<String, usize> = meta_data
+            .files
+            .iter()
+            .map(|(db_name, tables)| (db_name.clone(), tables.values().map(HashMap::len).sum()))
+            .collect();
+
+        CacheStats {
+            file_count: meta_data.locations.len(),
+            size_bytes: self.current_size_bytes(),
+            database_file_counts,
+            hits: self.hits.load(Ordering::SeqCst),
+            misses: self.misses.load(Ordering::SeqCst),
+        }
+    }
+
+    /// The names of every database with files in the cache, in sorted order
+    pub fn databases(&self) -> Vec<String> {
+        let mut databases: Vec<String> = self.meta_data.read().files.keys().cloned().collect();
+        databases.sort();
+        databases
+    }
+
+    /// Whether the cache holds any files for the given database
+    pub fn contains_database(&self, database_name: &str) -> bool {
+        self.meta_data.read().files.contains_key(database_name)
+    }
+
+    /// Whether the cache holds any files for the given table
+    pub fn contains_table(&self, database_name: &str, table_name: &str) -> bool {
+        self.meta_data
+            .read()
+            .files
+            .get(database_name)
+            .is_some_and(|tables| tables.contains_key(table_name))
+    }
+
+    /// The names of every table in the given database with files in the
+    /// cache, in sorted order
+    pub fn tables(&self, database_name: &str) -> Vec<String> {
+        let mut tables: Vec<String> = self
+            .meta_data
+            .read()
+            .files
+            .get(database_name)
+            .map(|tables| tables.keys().cloned().collect())
+            .unwrap_or_default();
+        tables.sort();
+        tables
+    }
+
+    /// Summarize the cached files of the given table, or `None` if it has no
+    /// files in the cache
+    pub fn table_summary(
+        &self,
+        database_name: &str,
+        table_name: &str,
+    ) -> Option<TableCacheSummary> {
+        let meta_data = self.meta_data.read();
+        let files = meta_data.files.get(database_name)?.get(table_name)?;
+
+        files.values().map(|cached| &cached.file).fold(
+            None,
+            |summary: Option<TableCacheSummary>, file| {
+                Some(match summary {
+                    None => TableCacheSummary {
+                        file_count: 1,
+                        total_rows: file.row_count,
+                        min_time: file.min_time,
+                        max_time: file.max_time,
+                        total_bytes: file.size_bytes,
+                    },
+                    Some(summary) => TableCacheSummary {
+                        file_count: summary.file_count + 1,
+                        total_rows: summary.total_rows + file.row_count,
+                        min_time: summary.min_time.min(file.min_time),
+                        max_time: summary.max_time.max(file.max_time),
+                        total_bytes: summary.total_bytes + file.size_bytes,
+                    },
+                })
+            },
+        )
+    }
+
+    /// Get the metadata of the cached parquet file at `path`. This counts as
+    /// a use of the file for eviction.
+    pub fn get_parquet_file(&self, path: &str) -> Option<ParquetFile> {
+        let meta_data = self.meta_data.read();
+        let cached = meta_data.get(path)?;
+        self.touch(cached);
+        Some(cached.file.clone())
+    }
+
+    /// Get the metadata of all cached parquet files for the given database
+    /// and table. This counts as a use of each of the files for eviction.
+    pub fn get_parquet_files(&self, database_name: &str, table_name: &str) -> Vec<ParquetFile> {
+        self.meta_data
+            .read()
+            .files
+            .get(database_name)
+            .and_then(|tables| tables.get(table_name))
+            .map(|files| {
+                files
+                    .values()
+                    .map(|cached| {
+                        self.touch(cached);
+                        cached.file.clone()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Get the metadata of the cached parquet files for the given database and
+    /// table whose time range overlaps `[min_time, max_time]`. Both ends of the
+    /// range are inclusive, so a file that ends at `min_time` or starts at
+    /// `max_time` is returned. This counts as a use of each returned file for
+    /// eviction.
+    pub fn get_parquet_files_in_range(
+        &self,
+        database_name: &str,
+        table_name: &str,
+        min_time: i64,
+        max_time: i64,
+    ) -> Vec<ParquetFile> {
+        self.meta_data
+            .read()
+            .files
+            .get(database_name)
+            .and_then(|tables| tables.get(table_name))
+            .map(|files| {
+                files
+                    .values()
+                    .filter(|cached| {
+                        cached.file.min_time <= max_time && cached.file.max_time >= min_time
+                    })
+                    .map(|cached| {
+                        self.touch(cached);
+                        cached.file.clone()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// When a file was last persisted to the given table, or `None` if one
+    /// never has been. Files evicted or removed from the table since don't
+    /// affect this, but clearing its database does.
+    pub fn table_last_persist(&self, db_name: &str, table_name: &str) -> Option<SystemTime> {
+        self.meta_data
+            .read()
+            .last_persist
+            .get(db_name)
+            .and_then(|tables| tables.get(table_name))
+            .map(|time| SystemTime::from(time.date_time()))
+    }
+
+    /// Take a copy of the metadata of every file in the cache under a single
+    /// lock, so that a consistent view across many tables can be queried
+    /// without going back to the cache. Unlike the other ways of listing
+    /// files this doesn't count as a use of the files for eviction.
+    pub fn snapshot(&self) -> CacheSnapshot {
+        let files = self
+            .meta_data
+            .read()
+            .files
+            .iter()
+            .map(|(db_name, tables)| {
+                let tables: HashMap<String, Vec<ParquetFile>> = tables
+                    .iter()
+                    .map(|(table_name, files)| {
+                        let files = files.values().map(|cached| cached.file.clone()).collect();
+                        (table_name.clone(), files)
+                    })
+                    .collect();
+                (db_name.clone(), tables)
+            })
+            .collect();
+
+        CacheSnapshot { files }
+    }
+
+    /// Mark the file as having just been used
+    fn touch(&self, cached: &CachedFile) {
+        cached.last_access.store(
+            self.access_clock.fetch_add(1, Ordering::SeqCst),
+            Ordering::SeqCst,
+        );
+    }
+
+    /// The version of the last file persisted to `path`, or 0 if one never
+    /// has been. The version isn't reset when the file is removed or
+    /// evicted, so this is the version a conditional persist to `path` has
+    /// to expect. Returns [`Error::PathInOtherTable`] if the file cached at
+    /// `path` belongs to another database or table, as a persist to `path`
+    /// for this table would.
+    pub fn parquet_file_version(&self, db_name: &str, table_name: &str, path: &str) -> Result<u64> {
+        self.meta_data
+            .read()
+            .table_version_of(db_name, table_name, path)
+    }
+
+    /// Returns the current version of the file at `path`, or an error if it
+    /// isn't the expected version or the file cached at `path` belongs to
+    /// another table
+    fn check_version(
+        meta_data: &MetaData,
+        db_name: &str,
+        table_name: &str,
+        path: &str,
+        expected_version: Option<u64>,
+    ) -> Result<u64> {
+        let actual = meta_data.table_version_of(db_name, table_name, path)?;
+        match expected_version {
+            Some(expected) if expected != actual => {
+                Err(Error::VersionConflict { expected, actual })
+            }
+            _ => Ok(actual),
+        }
+    }
+
+    /// Returns an error if the time range of a file to be cached is inverted
+    fn check_time_range(min_time: i64, max_time: i64) -> Result<()> {
+        if min_time > max_time {
+            return Err(Error::InvalidTimeRange { min_time, max_time });
+        }
+        Ok(())
+    }
+
+    /// The total size in bytes of all cached parquet files across every table
+    /// in the given database
+    pub fn database_bytes(&self, database_name: &str) -> u64 {
+        self.meta_data
+            .read()
+            .files
+            .get(database_name)
+            .map(|tables| {
+                tables
+                    .values()
+                    .flat_map(|files| files.values())
+                    .map(|cached| cached.file.size_bytes)
+                    .sum::<u64>()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Serialize the record batches to parquet and store the file in the
+    /// cache. If no `path` is given then one is generated by the cache's
+    /// [`PathScheme`]. Returns the path the file was stored at.
+    ///
+    /// If adding the file would take the cache over its size limit the least
+    /// recently used files in the cache are evicted until it fits. A file
+    /// that is larger than the limit on its own could never fit, so rather
+    /// than emptying the cache for it it's handled by the cache's
+    /// [`OversizedFilePolicy`] as if it were over the file size limit.
+    ///
+    /// If `expected_version` is given the persist only goes ahead if the
+    /// last file persisted to `path` is at that version (0 meaning nothing
+    /// has ever been persisted there), otherwise [`Error::VersionConflict`]
+    /// is returned. See [`ParquetCache::parquet_file_version`]. A file
+    /// cached at `path` for another table is never replaced, and
+    /// [`Error::PathInOtherTable`] is returned instead.
+    /// The version is checked before any work is done, and as only one
+    /// persist to a path runs at a time it can't change before the file is
+    /// recorded in the cache.
+    ///
+    /// Returns [`Error::InvalidTimeRange`] without doing any work if
+    /// `min_time` is greater than `max_time`.
+    ///
+    /// Replacing a file isn't read safe. The new file is written over the
+    /// old one in place before the cache's metadata is updated, so while the
+    /// persist is running, and for any reader that got the file's
+    /// [`ParquetFile`] before it finished, the object doesn't match its
+    /// recorded size or checksum and [`ParquetCache::load_parquet_file`] may
+    /// return [`Error::Corrupted`]. Persist to a fresh path, such as one
+    /// generated by the cache's [`PathScheme`], if the file may be being
+    /// read.
+    ///
+    /// Only one persist to a path runs at a time. If another persist to
+    /// `path` is already in flight this waits on it and, when no
+    /// `expected_version` is given, returns its result without serializing
+    /// `record_batches`. Should the other persist fail, or should an
+    /// `expected_version` be given, this one goes ahead with its own record
+    /// batches once the other has finished, checking the version again
+    /// first.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn persist_parquet_file(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        min_time: i64,
+        max_time: i64,
+        record_batches: SendableRecordBatchStream,
+        path: Option<ObjPath>,
+        expected_version: Option<u64>,
+    ) -> Result<ObjPath> {
+        Self::check_time_range(min_time, max_time)?;
+
+        // Generate a path for the file if one wasn't given
+        let path = path.unwrap_or_else(|| self.generate_path(db_name, table_name));
+
+        let guard = loop {
+            let in_flight_persist = {
+                let mut in_flight = self.in_flight.lock();
+                match in_flight.persists.get(path.as_ref()) {
+                    Some(persist) => persist.clone(),
+                    None => break InFlightGuard::register(&self.in_flight, &mut in_flight, &path),
+                }
+            };
+
+            // A conditional persist can't share another's result as its
+            // expected version may no longer hold, so it waits for the other
+            // to finish and then goes through the version check itself
+            let result = in_flight_persist.await;
+            if expected_version.is_none() {
+                // The other persist may have been for another table
+                if let Ok(path) = result {
+                    return self
+                        .meta_data
+                        .read()
+                        .table_version_of(db_name, table_name, path.as_ref())
+                        .map(|_| path);
+                }
+            }
+        };
+
+        let path = self
+            .persist(
+                db_name,
+                table_name,
+                min_time,
+                max_time,
+                record_batches,
+                path,
+                expected_version,
+            )
+            .await?;
+        guard.finish();
+
+        Ok(path)
+    }
+
+    /// Persist the record batches to `path` without checking for other
+    /// persists to it that are in flight
+    #[allow(clippy::too_many_arguments)]
+    async fn persist(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        min_time: i64,
+        max_time: i64,
+        record_batches: SendableRecordBatchStream,
+        path: ObjPath,
+        expected_version: Option<u64>,
+    ) -> Result<ObjPath> {
+        // Nothing else can change the path's version while this persist is
+        // in flight, so it's only checked the once
+        let version = Self::check_version(
+            &self.meta_data.read(),
+            db_name,
+            table_name,
+            path.as_ref(),
+            expected_version,
+        )? + 1;
+
+        // Any file already at the path is overwritten here, before the
+        // metadata is updated, which is why replacing a file isn't read safe
+        let written = self
+            .write_file(
+                db_name,
+                table_name,
+                min_time,
+                max_time,
+                record_batches,
+                &path,
+            )
+            .await?;
+
+        let events = self.event_sender();
+        let inserted = match &events {
+            Some(_) if written.cached => Some(written.file.clone()),
+            _ => None,
+        };
+
+        let mut replaced = None;
+        let evicted = {
+            let mut meta_data = self.meta_data.write();
+            let evicted = if written.cached {
+                self.insert_file(&mut meta_data, db_name, table_name, written, version)
+            } else {
+                // The file cached at the path, if there is one, has been
+                // superseded by a file that isn't cached, so it must not be
+                // handed out from the cache any more
+                meta_data.versions.insert(path.to_string(), version);
+                replaced = meta_data.remove(path.as_ref());
+                if let Some(replaced) = &replaced {
+                    self.size_bytes
+                        .fetch_sub(replaced.file.size_bytes, Ordering::SeqCst);
+                }
+                vec![]
+            };
+            meta_data.record_persist(db_name, table_name, self.time_provider.now());
+            evicted
+        };
+
+        if let (Some(events), Some(file)) = (events, inserted) {
+            // Sending only fails if every receiver has since been dropped
+            let _ = events.send(CacheEvent::Inserted(file));
+        }
+        if let Some(replaced) = replaced {
+            if let Err(e) = self.object_store.delete(&path).await {
+                error!(
+                    %e,
+                    %path,
+                    "failed to delete superseded parquet file from the cache"
+                );
+            }
+            if let Some(events) = self.event_sender() {
+                let _ = events.send(CacheEvent::Removed(replaced.file.path));
+            }
+        }
+        self.delete_evicted(evicted).await;
+
+        Ok(path)
+    }
+
+    /// Generate the path of a new file in the given database and table
+    fn generate_path(&self, db_name: &str, table_name: &str) -> ObjPath {
+        self.path_scheme
+            .path(db_name, table_name, (self.new_uuid.0)())
+    }
+
+    /// The object store that a file put by [`ParquetCache::write_file`] is
+    /// in, depending on whether it's to be cached
+    fn store_of(&self, cached: bool) -> &Arc<dyn ObjectStore> {
+        match (&self.oversized_file_policy, cached) {
+            (OversizedFilePolicy::StoreUncached(store), false) => store,
+            _ => &self.object_store,
+        }
+    }
+
+    /// Serialize the record batches to parquet and put the file in the
+    /// cache's object store at `path`, without recording it in the cache's
+    /// metadata. The file's size is checked against the cache's file size
+    /// limit once it has been serialized, and a file over the limit is put
+    /// in the store given by the [`OversizedFilePolicy`] instead if it
+    /// isn't rejected.
+    async fn write_file(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        min_time: i64,
+        max_time: i64,
+        record_batches: SendableRecordBatchStream,
+        path: &ObjPath,
+    ) -> Result<WrittenFile> {
+        // The serialization's memory reservation is released when it's done
+        let mem_pool: Arc<dyn MemoryPool> = Arc::<CacheMemoryPool>::clone(&self.mem_pool);
+        let compression = self.table_compression(db_name, table_name);
+        let parquet = serialize_to_parquet(mem_pool, record_batches, compression).await?;
+        let size_bytes = parquet.bytes.len() as u64;
+        let row_count = parquet.meta_data.num_rows as u64;
+        let checksum = self
+            .verify_checksums
+            .then(|| crc32fast::hash(&parquet.bytes));
+
+        // A file larger than the whole cache could never fit in it
+        let max_file_bytes = self
+            .max_file_bytes
+            .map_or(self.max_size_bytes, |max_file_bytes| {
+                max_file_bytes.min(self.max_size_bytes)
+            });
+        let cached = if size_bytes > max_file_bytes {
+            match self.oversized_file_policy {
+                OversizedFilePolicy::Reject => {
+                    return Err(Error::FileTooLarge {
+                        size_bytes,
+                        max_file_bytes,
+                    })
+                }
+                OversizedFilePolicy::StoreUncached(_) => false,
+            }
+        } else {
+            true
+        };
+
+        self.store_of(cached).put(path, parquet.bytes).await?;
+
+        Ok(WrittenFile {
+            file: ParquetFile {
+                path: path.to_string(),
+                size_bytes,
+                row_count,
+                min_time,
+                max_time,
+            },
+            checksum,
+            cached,
+        })
+    }
+
+    /// Serialize each of the record batch streams to its own parquet file and
+    /// store them all in the cache, returning the generated paths of the
+    /// files in the same order as the streams. The files are all recorded
+    /// in the cache under a single lock once every one of them has been
+    /// stored, rather than locking the cache once per file.
+    ///
+    /// If any of the files fails to be stored then none of them are cached
+    /// and those already put in the object store are deleted again.
+    pub async fn persist_parquet_files(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        batches: Vec<(i64, i64, SendableRecordBatchStream)>,
+    ) -> Result<Vec<ObjPath>> {
+        for (min_time, max_time, _) in &batches {
+            Self::check_time_range(*min_time, *max_time)?;
+        }
+
+        let mut paths = Vec::with_capacity(batches.len());
+        let mut written = Vec::with_capacity(batches.len());
+        for (min_time, max_time, record_batches) in batches {
+            let path = self.generate_path(db_name, table_name);
+            let result = self
+                .write_file(
+                    db_name,
+                    table_name,
+                    min_time,
+                    max_time,
+                    record_batches,
+                    &path,
+                )
+                .await;
+            match result {
+                Ok(file) => {
+                    paths.push(path);
+                    written.push(file);
+                }
+                Err(e) => {
+                    for (path, written) in paths.iter().zip(&written) {
+                        self.delete_unrecorded(self.store_of(written.cached), path)
+                            .await;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let events = self.event_sender();
+        let inserted: Vec<ParquetFile> = match &events {
+            Some(_) => written
+                .iter()
+                .filter(|written| written.cached)
+                .map(|written| written.file.clone())
+                .collect(),
+            None => vec![],
+        };
+
+        let evicted = {
+            let mut meta_data = self.meta_data.write();
+            // The paths are all new so there's no version to check
+            let evicted: Vec<Evicted> = written
+                .into_iter()
+                .filter(|written| written.cached)
+                .flat_map(|written| {
+                    let version = meta_data.version_of(&written.file.path) + 1;
+                    self.insert_file(&mut meta_data, db_name, table_name, written, version)
+                })
+                .collect();
+            meta_data.record_persist(db_name, table_name, self.time_provider.now());
+            evicted
+        };
+        if let Some(events) = events {
+            for file in inserted {
+                let _ = events.send(CacheEvent::Inserted(file));
+            }
+        }
+        self.delete_evicted(evicted).await;
+
+        Ok(paths)
+    }
+
+    /// Delete the object of a file that was put in `object_store` but
+    /// couldn't be recorded in the cache
+    async fn delete_unrecorded(&self, object_store: &Arc<dyn ObjectStore>, path: &ObjPath) {
+        if let Err(e) = object_store.delete(path).await {
+            error!(
+                %e,
+                %path,
+                "failed to delete parquet file from the cache after a failed persist"
+            );
+        }
+    }
+
+    /// Pass the files evicted from the cache to the eviction callback, spill
+    /// those that are to be spilled and delete their objects. Failures are
+    /// only logged as the files are already gone from the cache's metadata.
+    /// A file that fails to spill is left in the cache's object store rather
+    /// than lost.
+    async fn delete_evicted(&self, evicted: Vec<Evicted>) {
+        let events = if evicted.is_empty() {
+            None
+        } else {
+            self.event_sender()
+        };
+        for Evicted { file, spill } in evicted {
+            if let Some(on_evict) = &self.on_evict.0 {
+                on_evict(&file);
+            }
+            if let Some(events) = &events {
+                let _ = events.send(CacheEvent::Evicted(file.clone()));
+            }
+
+            let evicted_path = ObjPath::from(file.path);
+            if let Some(spill_store) = self.spill_store.as_ref().filter(|_| spill) {
+                if let Err(e) = self.spill(spill_store, &evicted_path).await {
+                    error!(
+                        %e,
+                        path = %evicted_path,
+                        "failed to spill evicted parquet file from the cache"
+                    );
+                    continue;
+                }
+            }
+            if let Err(e) = self.object_store.delete(&evicted_path).await {
+                error!(
+                    %e,
+                    path = %evicted_path,
+                    "failed to delete evicted parquet file from the cache"
+                );
+            }
+        }
+    }
+
+    /// Copy a file's object from the cache's object store to `spill_store`
+    async fn spill(&self, spill_store: &Arc<dyn ObjectStore>, path: &ObjPath) -> Result<()> {
+        let bytes = self.object_store.get(path).await?.bytes().await?;
+        spill_store.put(path, bytes).await?;
+        Ok(())
+    }
+
+    /// Index the parquet files already in the cache's object store under
+    /// `prefix` as files of the given database and table, e.g. to warm the
+    /// cache from a durable object store on startup. The row count and time
+    /// range of each file are read from its parquet footer. A file without
+    /// statistics for its time column is given the widest possible time
+    /// range so that it is never pruned from a query. Files that are already
+    /// in the cache are skipped, as are objects that aren't parquet files.
+    ///
+    /// Warming never evicts anything, as evicting a file deletes its object
+    /// from the very store being warmed. Files that don't fit in the cache's
+    /// size limit alongside those already cached are left unindexed, and
+    /// table row budgets aren't enforced until the next persist.
+    ///
+    /// Returns the number of files that were indexed.
+    pub async fn load_metadata_from_store(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        prefix: &ObjPath,
+    ) -> Result<usize> {
+        let objects: Vec<_> = self.object_store.list(Some(prefix)).try_collect().await?;
+
+        let events = self.event_sender();
+        let mut indexed = 0;
+        for object in objects {
+            if self
+                .meta_data
+                .read()
+                .get(object.location.as_ref())
+                .is_some()
+            {
+                continue;
+            }
+
+            let path = object.location.to_string();
+            let size_bytes = object.size as u64;
+            let mut reader = ParquetObjectReader::new(Arc::clone(&self.object_store), object);
+            let parquet_meta_data = match reader.get_metadata().await {
+                Ok(parquet_meta_data) => parquet_meta_data,
+                Err(e) => {
+                    warn!(
+                        %e,
+                        %path,
+                        "skipping object that isn't a parquet file while warming the cache"
+                    );
+                    continue;
+                }
+            };
+            let (min_time, max_time) = time_range(&parquet_meta_data);
+
+            let file = ParquetFile {
+                path,
+                size_bytes,
+                row_count: parquet_meta_data.file_metadata().num_rows() as u64,
+                min_time,
+                max_time,
+            };
+            // A persist in flight to the path records its own file, and must
+            // be the only thing to change the path's version while it runs
+            let in_flight = self.in_flight.lock();
+            let mut meta_data = self.meta_data.write();
+            if in_flight.persists.contains_key(&file.path)
+                || meta_data.get(&file.path).is_some()
+                || self.size_bytes.load(Ordering::SeqCst) + size_bytes > self.max_size_bytes
+            {
+                continue;
+            }
+            let version = meta_data.version_of(&file.path) + 1;
+            let inserted = events.as_ref().map(|_| file.clone());
+            self.add_file(&mut meta_data, db_name, table_name, file, None, version);
+            drop(meta_data);
+            drop(in_flight);
+
+            if let (Some(events), Some(file)) = (&events, inserted) {
+                let _ = events.send(CacheEvent::Inserted(file));
+            }
+            indexed += 1;
+        }
+
+        Ok(indexed)
+    }
+
+    /// Insert a file whose object has been put in the cache's object store
+    /// into the cache's metadata at the given version, evicting the least
+    /// recently used files to make room for it and then the table's oldest
+    /// files if it's over its row budget. Returns the evicted files, whose
+    /// objects still need deleting.
+    fn insert_file(
+        &self,
+        meta_data: &mut MetaData,
+        db_name: &str,
+        table_name: &str,
+        written: WrittenFile,
+        version: u64,
+    ) -> Vec<Evicted> {
+        let WrittenFile { file, checksum, .. } = written;
+
+        // The file being replaced doesn't count towards the size of the
+        // cache and its object has already been overwritten
+        if let Some(replaced) = meta_data.remove(&file.path) {
+            self.size_bytes
+                .fetch_sub(replaced.file.size_bytes, Ordering::SeqCst);
+        }
+
+        let mut evicted = Vec::new();
+        while self.size_bytes.load(Ordering::SeqCst) + file.size_bytes > self.max_size_bytes {
+            let Some(cached) = meta_data
+                .eviction_candidate()
+                .and_then(|evict_path| meta_data.remove(&evict_path))
+            else {
+                break;
+            };
+            self.size_bytes
+                .fetch_sub(cached.file.size_bytes, Ordering::SeqCst);
+            evicted.push(Evicted {
+                file: cached.file,
+                spill: false,
+            });
+        }
+
+        let path = file.path.clone();
+        self.add_file(meta_data, db_name, table_name, file, checksum, version);
+
+        if let Some(max_rows) = self.table_row_budget(db_name, table_name) {
+            while let Some(cached) = meta_data
+                .over_row_budget_candidate(db_name, table_name, max_rows, &path)
+                .and_then(|evict_path| meta_data.remove(&evict_path))
+            {
+                self.size_bytes
+                    .fetch_sub(cached.file.size_bytes, Ordering::SeqCst);
+                evicted.push(Evicted {
+                    file: cached.file,
+                    spill: true,
+                });
+            }
+        }
+
+        evicted
+    }
+
+    /// Add a file to the cache's metadata and size as its most recently used
+    /// file, without evicting anything
+    fn add_file(
+        &self,
+        meta_data: &mut MetaData,
+        db_name: &str,
+        table_name: &str,
+        file: ParquetFile,
+        checksum: Option<u32>,
+        version: u64,
+    ) {
+        let size_bytes = file.size_bytes;
+        meta_data.insert(
+            db_name,
+            table_name,
+            CachedFile {
+                file,
+                version,
+                last_access: AtomicU64::new(self.access_clock.fetch_add(1, Ordering::SeqCst)),
+                checksum,
+            },
+        );
+        self.size_bytes.fetch_add(size_bytes, Ordering::SeqCst);
+    }
+
+    /// Get the path of the file cached under `key`, persisting the stream
+    /// yielded by `produce` to it if it isn't cached yet. `key` is used as the
+    /// path of the file in the cache.
+    ///
+    /// Concurrent callers for the same key share a single in flight persist,
+    /// so `produce` is only run by whichever caller gets there first and the
+    /// others wait on its result. A caller that finds a
+    /// [`ParquetCache::persist_parquet_file`] to the key in flight waits on
+    /// it instead, and runs `produce` itself should that persist fail.
+    ///
+    /// If every caller waiting on a persist started by this is cancelled the
+    /// persist is abandoned, and the next caller for the key starts afresh.
+    ///
+    /// Returns [`Error::InvalidTimeRange`] without running `produce` if
+    /// `min_time` is greater than `max_time`, and
+    /// [`Error::PathInOtherTable`] if a file is cached at `key` for another
+    /// database or table.
+    pub async fn get_or_persist<F, Fut>(
+        self: &Arc<Self>,
+        db_name: &str,
+        table_name: &str,
+        key: &str,
+        min_time: i64,
+        max_time: i64,
+        produce: F,
+    ) -> Result<ObjPath>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<SendableRecordBatchStream, DataFusionError>> + Send + 'static,
+    {
+        Self::check_time_range(min_time, max_time)?;
+        let path = ObjPath::from(key);
+        let mut produce = Some(produce);
+
+        loop {
+            let (waiter, ours) = {
+                // The in flight lock is held while checking the metadata so that a
+                // persist can't finish between the two checks. Persists record
+                // their file before removing themselves from the in flight map.
+                let mut in_flight = self.in_flight.lock();
+                {
+                    let meta_data = self.meta_data.read();
+                    // A file cached at the key for another table is never
+                    // taken over
+                    meta_data.table_version_of(db_name, table_name, key)?;
+                    if meta_data.get(key).is_some() {
+                        return Ok(path);
+                    }
+                }
+
+                match in_flight.persists.get(key) {
+                    Some(persist) => {
+                        let waiter = in_flight.waiter_driven.get(key).map(|&id| WaiterGuard {
+                            in_flight: &self.in_flight,
+                            path: key,
+                            id,
+                            persist: Some(persist.clone()),
+                        });
+                        (waiter.ok_or_else(|| persist.clone()), false)
+                    }
+                    None => {
+                        let produce = produce
+                            .take()
+                            .expect("produce is only taken by a persist that is returned");
+                        let cache = Arc::clone(self);
+                        let db_name = db_name.to_string();
+                        let table_name = table_name.to_string();
+                        let persist_key = key.to_string();
+                        let persist = async move {
+                            let result: Result<ObjPath> = async {
+                                let record_batches = produce().await?;
+                                cache
+                                    .persist(
+                                        &db_name,
+                                        &table_name,
+                                        min_time,
+                                        max_time,
+                                        record_batches,
+                                        ObjPath::from(persist_key.as_str()),
+                                        None,
+                                    )
+                                    .await
+                            }
+                            .await;
+
+                            let mut in_flight = cache.in_flight.lock();
+                            in_flight.persists.remove(&persist_key);
+                            in_flight.waiter_driven.remove(&persist_key);
+                            result.map_err(Arc::new)
+                        }
+                        .boxed()
+                        .shared();
+
+                        let id = in_flight.next_id;
+                        in_flight.next_id += 1;
+                        in_flight.persists.insert(key.to_string(), persist.clone());
+                        in_flight.waiter_driven.insert(key.to_string(), id);
+                        let waiter = WaiterGuard {
+                            in_flight: &self.in_flight,
+                            path: key,
+                            id,
+                            persist: Some(persist),
+                        };
+                        (Ok(waiter), true)
+                    }
+                }
+            };
+
+            let result = match waiter {
+                Ok(waiter) => waiter.wait().await,
+                // A persist started by `persist_parquet_file` carries on
+                // whether or not anything waits on it
+                Err(persist) => persist.await,
+            };
+            return match result {
+                Ok(path) if ours => Ok(path),
+                // Someone else's persist to the key may have been for another
+                // table
+                Ok(path) => self
+                    .meta_data
+                    .read()
+                    .table_version_of(db_name, table_name, key)
+                    .map(|_| path),
+                // A `persist_parquet_file` to the same path failed, which says
+                // nothing about whether `produce` will, so have a go at it
+                Err(e) if !ours && matches!(*e, Error::InFlightPersistFailed { .. }) => continue,
+                Err(e) => Err(Error::SharedPersist(e)),
+            };
+        }
+    }
+
+    /// Load the bytes of a cached parquet file. This counts as a use of the
+    /// file for eviction, and as a hit or miss in the cache's [`CacheStats`].
+    ///
+    /// If a checksum was recorded for the file when it was persisted and the
+    /// loaded bytes don't match it then [`Error::Corrupted`] is returned.
+    pub async fn load_parquet_file(&self, path: ObjPath) -> Result<Bytes> {
+        let checksum = self.record_load(&path);
+        let bytes = self.object_store.get(&path).await?.bytes().await?;
+
+        match checksum {
+            Some(checksum) if crc32fast::hash(&bytes) != checksum => Err(Error::Corrupted {
+                path: path.to_string(),
+            }),
+            _ => Ok(bytes),
+        }
+    }
+
+    /// Stream the bytes of a cached parquet file from the object store
+    /// rather than loading the whole file into memory at once. Like
+    /// [`ParquetCache::load_parquet_file`] this counts as a use of the file
+    /// for eviction and as a hit or miss in the cache's [`CacheStats`]. The
+    /// file isn't checked against its checksum as its bytes are handed out
+    /// before all of them have been read.
+    pub async fn load_parquet_stream(
+        &self,
+        path: ObjPath,
+    ) -> Result<impl Stream<Item = Result<Bytes>> + Send> {
+        self.record_load(&path);
+        Ok(self
+            .object_store
+            .get(&path)
+            .await?
+            .into_stream()
+            .map_err(Error::from))
+    }
+
+    /// Record a load of the file at `path`, touching it if it's cached.
+    /// Returns the checksum recorded for the file, if there is one.
+    fn record_load(&self, path: &ObjPath) -> Option<u32> {
+        match self.meta_data.read().get(path.as_ref()) {
+            Some(cached) => {
+                self.touch(cached);
+                self.hits.fetch_add(1, Ordering::SeqCst);
+                cached.checksum
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::SeqCst);
+                None
+            }
+        }
+    }
+
+    /// Remove a parquet file from the cache. Removing a file that isn't in
+    /// the cache, e.g. because it was already removed or has been evicted,
+    /// is a no-op.
+    pub async fn remove_parquet_file(&self, path: ObjPath) -> Result<()> {
+        let removed = {
+            let mut meta_data = self.meta_data.write();
+            let removed = meta_data.remove(path.as_ref());
+            if let Some(removed) = &removed {
+                self.size_bytes
+                    .fetch_sub(removed.file.size_bytes, Ordering::SeqCst);
+            }
+            removed
+        };
+
+        let result = match self.object_store.delete(&path).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+        };
+
+        if let (Some(events), Some(removed)) = (self.event_sender(), removed) {
+            let _ = events.send(CacheEvent::Removed(removed.file.path));
+        }
+
+        result
+    }
+
+    /// Remove every cached file for the given database. A failure to delete
+    /// one of the files doesn't stop the rest from being deleted, and all of
+    /// the failures are returned together in [`Error::ClearDatabase`]. The
+    /// files are removed from the cache's metadata either way.
+    pub async fn clear_database(&self, db_name: &str) -> Result<()> {
+        let removed = {
+            let mut meta_data = self.meta_data.write();
+            let removed = meta_data.remove_database(db_name);
+            let removed_bytes: u64 = removed.iter().map(|cached| cached.file.size_bytes).sum();
+            self.size_bytes.fetch_sub(removed_bytes, Ordering::SeqCst);
+            removed
+        };
+
+        let events = self.event_sender();
+        let mut errors = Vec::new();
+        for cached in removed {
+            let path = ObjPath::from(cached.file.path.as_str());
+            if let Err(e) = self.object_store.delete(&path).await {
+                errors.push(e);
+            }
+            if let Some(events) = &events {
+                let _ = events.send(CacheEvent::Removed(cached.file.path));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::ClearDatabase {
+                db_name: db_name.to_string(),
+                errors,
+            })
+        }
+    }
+
+    /// The object store that cached files are stored in
+    pub fn object_store(&self) -> Arc<dyn ObjectStore> {
+        Arc::clone(&self.object_store)
+    }
+}
+
+/// The time range covered by a parquet file according to the statistics of
+/// its time column, or the widest possible range if it has none
+fn time_range(parquet_meta_data: &ParquetMetaData) -> (i64, i64) {
+    let full_range = (i64::MIN, i64::MAX);
+    let Some(time_column) = parquet_meta_data
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .position(|column| column.name() == TIME_COLUMN_NAME)
+    else {
+        return full_range;
+    };
+
+    parquet_meta_data
+        .row_groups()
+        .iter()
+        .try_fold(
+            (i64::MAX, i64::MIN),
+            |(min_time, max_time), row_group| match row_group.column(time_column).statistics() {
+                Some(Statistics::Int64(stats)) if stats.has_min_max_set() => {
+                    Some((min_time.min(*stats.min()), max_time.max(*stats.max())))
+                }
+                _ => None,
+            },
+        )
+        .filter(|(min_time, max_time)| min_time <= max_time)
+        .unwrap_or(full_range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Int32Array, TimestampNanosecondArray};
+    use arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+    use arrow::record_batch::RecordBatch;
+    use datafusion::execution::memory_pool::UnboundedMemoryPool;
+    use datafusion::physical_plan::stream::{
+        RecordBatchReceiverStreamBuilder, RecordBatchStreamAdapter,
+    };
+    use futures_util::stream::BoxStream;
+    use futures_util::StreamExt;
+    use iox_time::MockProvider;
+    use object_store::local::LocalFileSystem;
+    use object_store::{
+        GetOptions, GetResult, ListResult, MultipartId, ObjectMeta, PutOptions, PutResult,
+    };
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+    use tokio::io::AsyncWrite;
+
+    fn make_cache() -> ParquetCache {
+        let mem_pool: Arc<dyn MemoryPool> = Arc::new(UnboundedMemoryPool::default());
+        ParquetCache::new(&mem_pool, DEFAULT_MAX_SIZE_BYTES)
+    }
+
+    async fn make_stream(ids: Vec<i32>) -> SendableRecordBatchStream {
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int32, false)]));
+        let stream_builder = RecordBatchReceiverStreamBuilder::new(schema.clone(), 5);
+
+        let id_array = Int32Array::from(ids);
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(id_array)]).unwrap();
+        stream_builder.tx().send(Ok(batch)).await.unwrap();
+
+        stream_builder.build()
+    }
+
+    /// An object store for testing how the cache copes with its object store
+    /// misbehaving
+    #[derive(Debug, Default)]
+    struct TestObjectStore {
+        inner: InMemory,
+        /// Fail every put while this is set
+        fail_puts: AtomicBool,
+        /// Puts wait on a read lock of this, so holding the write lock holds
+        /// up every put
+        put_gate: tokio::sync::RwLock<()>,
+        puts_started: AtomicUsize,
+    }
+
+    impl fmt::Display for TestObjectStore {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "TestObjectStore")
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ObjectStore for TestObjectStore {
+        async fn put_opts(
+            &self,
+            location: &ObjPath,
+            bytes: Bytes,
+            opts: PutOptions,
+        ) -> object_store::Result<PutResult> {
+            self.puts_started.fetch_add(1, Ordering::SeqCst);
+            let _gate = self.put_gate.read().await;
+            if self.fail_puts.load(Ordering::SeqCst) {
+                return Err(object_store::Error::Generic {
+                    store: "TestObjectStore",
+                    source: "put failed".into(),
+                });
+            }
+            self.inner.put_opts(location, bytes, opts).await
+        }
+
+        async fn put_multipart(
+            &self,
+            location: &ObjPath,
+        ) -> object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+            self.inner.put_multipart(location).await
+        }
+
+        async fn abort_multipart(
+            &self,
+            location: &ObjPath,
+            multipart_id: &MultipartId,
+        ) -> object_store::Result<()> {
+            self.inner.abort_multipart(location, multipart_id).await
+        }
+
+        async fn get_opts(
+            &self,
+            location: &ObjPath,
+            options: GetOptions,
+        ) -> object_store::Result<GetResult> {
+            self.inner.get_opts(location, options).await
+        }
+
+        async fn delete(&self, location: &ObjPath) -> object_store::Result<()> {
+            self.inner.delete(location).await
+        }
+
+        fn list(
+            &self,
+            prefix: Option<&ObjPath>,
+        ) -> BoxStream<'_, object_store::Result<ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+
+        async fn list_with_delimiter(
+            &self,
+            prefix: Option<&ObjPath>,
+        ) -> object_store::Result<ListResult> {
+            self.inner.list_with_delimiter(prefix).await
+        }
+
+        async fn copy(&self, from: &ObjPath, to: &ObjPath) -> object_store::Result<()> {
+            self.inner.copy(from, to).await
+        }
+
+        async fn copy_if_not_exists(
+            &self,
+            from: &ObjPath,
+            to: &ObjPath,
+        ) -> object_store::Result<()> {
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    fn make_cache_with_test_store() -> (Arc<ParquetCache>, Arc<TestObjectStore>) {
+        let store = Arc::new(TestObjectStore::default());
+        let object_store: Arc<dyn ObjectStore> = Arc::<TestObjectStore>::clone(&store);
+        let mem_pool: Arc<dyn MemoryPool> = Arc::new(UnboundedMemoryPool::default());
+        let cache =
+            ParquetCache::with_object_store(object_store, &mem_pool, DEFAULT_MAX_SIZE_BYTES);
+        (Arc::new(cache), store)
+    }
+
+    async fn stored_paths(object_store: &dyn ObjectStore) -> Vec<ObjPath> {
+        object_store
+            .list(None)
+            .map(|meta| meta.unwrap().location)
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn persist_load_and_remove() {
+        let cache = make_cache();
+
+        let path = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        let files = cache.get_parquet_files("db", "table");
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, path.to_string());
+        assert_eq!(files[0].row_count, 3);
+        assert_eq!(files[0].min_time, 0);
+        assert_eq!(files[0].max_time, 10);
+
+        let bytes = cache.load_parquet_file(path.clone()).await.unwrap();
+        assert_eq!(bytes.len() as u64, files[0].size_bytes);
+
+        cache.remove_parquet_file(path.clone()).await.unwrap();
+        assert!(cache.get_parquet_files("db", "table").is_empty());
+        assert!(cache.load_parquet_file(path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn remove_twice() {
+        let cache = make_cache();
+
+        let path = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        cache.remove_parquet_file(path.clone()).await.unwrap();
+        cache.remove_parquet_file(path).await.unwrap();
+        cache
+            .remove_parquet_file(ObjPath::from("db-table-never-cached"))
+            .await
+            .unwrap();
+        assert_eq!(cache.current_size_bytes(), 0);
+        assert_eq!(cache.stats().file_count, 0);
+    }
+
+    #[tokio::test]
+    async fn database_bytes() {
+        let cache = make_cache();
+
+        cache
+            .persist_parquet_file(
+                "db_one",
+                "cpu",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        cache
+            .persist_parquet_file(
+                "db_one",
+                "mem",
+                0,
+                10,
+                make_stream(vec![4, 5]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        cache
+            .persist_parquet_file(
+                "db_two",
+                "cpu",
+                0,
+                10,
+                make_stream((0..1_000).collect()).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        let db_one_bytes: u64 = ["cpu", "mem"]
+            .into_iter()
+            .flat_map(|table| cache.get_parquet_files("db_one", table))
+            .map(|file| file.size_bytes)
+            .sum();
+        let db_two_bytes: u64 = cache
+            .get_parquet_files("db_two", "cpu")
+            .into_iter()
+            .map(|file| file.size_bytes)
+            .sum();
+
+        assert!(db_one_bytes > 0);
+        assert!(db_two_bytes > 0);
+        assert_eq!(cache.database_bytes("db_one"), db_one_bytes);
+        assert_eq!(cache.database_bytes("db_two"), db_two_bytes);
+        assert_eq!(cache.database_bytes("db_three"), 0);
+    }
+
+    #[tokio::test]
+    async fn databases_and_tables() {
+        let cache = make_cache();
+        assert!(cache.databases().is_empty());
+
+        for (db_name, table_name) in [("db_two", "cpu"), ("db_one", "mem"), ("db_one", "cpu")] {
+            cache
+                .persist_parquet_file(
+                    db_name,
+                    table_name,
+                    0,
+                    10,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(cache.databases(), vec!["db_one", "db_two"]);
+        assert_eq!(cache.tables("db_one"), vec!["cpu", "mem"]);
+        assert_eq!(cache.tables("db_two"), vec!["cpu"]);
+        assert!(cache.tables("db_three").is_empty());
+    }
+
+    #[tokio::test]
+    async fn clear_database() {
+        let cache = make_cache();
+
+        let mut cleared = Vec::new();
+        for table_name in ["cpu", "cpu", "mem"] {
+            let path = cache
+                .persist_parquet_file(
+                    "db_one",
+                    table_name,
+                    0,
+                    10,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            cleared.push(path);
+        }
+        let kept = cache
+            .persist_parquet_file(
+                "db_two",
+                "cpu",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        cache.clear_database("db_one").await.unwrap();
+
+        for path in cleared {
+            assert!(matches!(
+                cache.object_store.get(&path).await,
+                Err(object_store::Error::NotFound { .. })
+            ));
+        }
+        assert_eq!(cache.databases(), vec!["db_two"]);
+        assert!(cache.get_parquet_files("db_one", "cpu").is_empty());
+        assert_eq!(cache.stats().file_count, 1);
+        assert_eq!(cache.current_size_bytes(), cache.database_bytes("db_two"));
+        assert!(cache.load_parquet_file(kept).await.is_ok());
+
+        // Clearing a database that isn't cached is a no-op
+        cache.clear_database("db_three").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_parquet_file() {
+        let cache = make_cache();
+
+        let path = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                5,
+                15,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        let file = cache.get_parquet_file(path.as_ref()).unwrap();
+        assert_eq!(file.path, path.to_string());
+        assert_eq!(file.row_count, 3);
+        assert_eq!(file.min_time, 5);
+        assert_eq!(file.max_time, 15);
+        assert_eq!(cache.get_parquet_files("db", "table"), vec![file]);
+
+        assert!(cache.get_parquet_file("db-table-missing").is_none());
+        cache.remove_parquet_file(path.clone()).await.unwrap();
+        assert!(cache.get_parquet_file(path.as_ref()).is_none());
+    }
+
+    #[tokio::test]
+    async fn persist_with_invalid_time_range() {
+        let cache = make_cache();
+
+        let err = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                10,
+                0,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidTimeRange {
+                min_time: 10,
+                max_time: 0
+            }
+        ));
+        assert_eq!(cache.stats().file_count, 0);
+
+        // A file holding a single timestamp is fine
+        let path = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                10,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let file = cache.get_parquet_file(path.as_ref()).unwrap();
+        assert_eq!((file.min_time, file.max_time), (10, 10));
+    }
+
+    #[tokio::test]
+    async fn table_summary() {
+        let cache = make_cache();
+        assert!(cache.table_summary("db", "table").is_none());
+
+        let mut total_bytes = 0;
+        for (min_time, max_time, ids) in [
+            (10, 20, vec![1]),
+            (0, 5, vec![1, 2]),
+            (15, 30, vec![1, 2, 3]),
+        ] {
+            let path = cache
+                .persist_parquet_file(
+                    "db",
+                    "table",
+                    min_time,
+                    max_time,
+                    make_stream(ids).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            total_bytes += cache.get_parquet_file(path.as_ref()).unwrap().size_bytes;
+        }
+        // Files for other tables aren't included
+        cache
+            .persist_parquet_file(
+                "db",
+                "other",
+                -10,
+                100,
+                make_stream(vec![1]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            cache.table_summary("db", "table"),
+            Some(TableCacheSummary {
+                file_count: 3,
+                total_rows: 6,
+                min_time: 0,
+                max_time: 30,
+                total_bytes,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn contains_database_and_table() {
+        let cache = make_cache();
+        assert!(!cache.contains_database("db"));
+        assert!(!cache.contains_table("db", "cpu"));
+
+        let path = cache
+            .persist_parquet_file(
+                "db",
+                "cpu",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert!(cache.contains_database("db"));
+        assert!(cache.contains_table("db", "cpu"));
+        assert!(!cache.contains_table("db", "mem"));
+        assert!(!cache.contains_database("other_db"));
+        assert!(!cache.contains_table("other_db", "cpu"));
+
+        // Neither is contained once their last file is gone
+        cache.remove_parquet_file(path).await.unwrap();
+        assert!(!cache.contains_database("db"));
+        assert!(!cache.contains_table("db", "cpu"));
+    }
+
+    #[tokio::test]
+    async fn persist_with_expected_version() {
+        let cache = make_cache();
+        let path = ObjPath::from("db-table-file");
+
+        // Nothing is cached at the path yet so it is at version 0
+        cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1]).await,
+                Some(path.clone()),
+                Some(0),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            cache
+                .parquet_file_version("db", "table", path.as_ref())
+                .unwrap(),
+            1
+        );
+
+        // Update from the version we just read
+        cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2]).await,
+                Some(path.clone()),
+                Some(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            cache
+                .parquet_file_version("db", "table", path.as_ref())
+                .unwrap(),
+            2
+        );
+
+        // A second update that also read version 1 has lost the race
+        let err = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                Some(path.clone()),
+                Some(1),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::VersionConflict {
+                expected: 1,
+                actual: 2
+            }
+        ));
+
+        // The conflicting write didn't replace the cached file
+        let files = cache.get_parquet_files("db", "table");
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].row_count, 2);
+        assert_eq!(
+            cache
+                .parquet_file_version("db", "table", path.as_ref())
+                .unwrap(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn path_in_other_table() {
+        let cache = make_cache();
+        let path = ObjPath::from("db-table-file");
+        cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1]).await,
+                Some(path.clone()),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let is_path_in_other_table = |err: &Error| {
+            matches!(err, Error::PathInOtherTable { db_name, table_name, .. }
+                if db_name == "db" && table_name == "table")
+        };
+        assert!(is_path_in_other_table(
+            &cache
+                .parquet_file_version("db", "other_table", path.as_ref())
+                .unwrap_err()
+        ));
+
+        // Neither a conditional nor an unconditional persist for another
+        // table takes the file over
+        for expected_version in [Some(0), None] {
+            let err = cache
+                .persist_parquet_file(
+                    "db",
+                    "other_table",
+                    0,
+                    10,
+                    make_stream(vec![1, 2]).await,
+                    Some(path.clone()),
+                    expected_version,
+                )
+                .await
+                .unwrap_err();
+            assert!(is_path_in_other_table(&err));
+        }
+        assert_eq!(cache.get_parquet_files("db", "table").len(), 1);
+        assert!(cache.get_parquet_files("db", "other_table").is_empty());
+        assert_eq!(cache.get_parquet_file(path.as_ref()).unwrap().row_count, 1);
+    }
+
+    #[tokio::test]
+    async fn stale_expected_version_after_remove() {
+        let cache = make_cache();
+        let path = ObjPath::from("db-table-file");
+        let persist = |ids, expected_version| {
+            let cache = &cache;
+            let path = path.clone();
+            async move {
+                cache
+                    .persist_parquet_file(
+                        "db",
+                        "table",
+                        0,
+                        10,
+                        make_stream(ids).await,
+                        Some(path),
+                        expected_version,
+                    )
+                    .await
+            }
+        };
+
+        persist(vec![1], Some(0)).await.unwrap();
+        cache.remove_parquet_file(path.clone()).await.unwrap();
+
+        // The version carries on from where the removed file left it
+        assert_eq!(
+            cache
+                .parquet_file_version("db", "table", path.as_ref())
+                .unwrap(),
+            1
+        );
+        persist(vec![1, 2], Some(1)).await.unwrap();
+        assert_eq!(
+            cache
+                .parquet_file_version("db", "table", path.as_ref())
+                .unwrap(),
+            2
+        );
+
+        // A writer that read version 1 before the remove has lost the race
+        let err = persist(vec![1, 2, 3], Some(1)).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::VersionConflict {
+                expected: 1,
+                actual: 2
+            }
+        ));
+        assert_eq!(cache.get_parquet_file(path.as_ref()).unwrap().row_count, 2);
+    }
+
+    #[tokio::test]
+    async fn get_or_persist_runs_produce_once() {
+        let cache = Arc::new(make_cache());
+        let produced = Arc::new(AtomicUsize::new(0));
+
+        let producer = |produced: Arc<AtomicUsize>| {
+            move || async move {
+                produced.fetch_add(1, Ordering::SeqCst);
+                // Give the other callers time to pile up behind this one
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok::<_, DataFusionError>(make_stream(vec![1, 2, 3]).await)
+            }
+        };
+
+        let callers = (0..10).map(|_| {
+            let cache = Arc::clone(&cache);
+            let produce = producer(Arc::clone(&produced));
+            tokio::spawn(async move {
+                cache
+                    .get_or_persist("db", "table", "db-table-derived", 0, 10, produce)
+                    .await
+                    .unwrap()
+            })
+        });
+
+        let paths = futures_util::future::join_all(callers).await;
+        for path in paths {
+            assert_eq!(path.unwrap(), ObjPath::from("db-table-derived"));
+        }
+
+        assert_eq!(produced.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get_parquet_files("db", "table").len(), 1);
+
+        // Now that it's cached produce isn't run at all
+        cache
+            .get_or_persist(
+                "db",
+                "table",
+                "db-table-derived",
+                0,
+                10,
+                producer(Arc::clone(&produced)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(produced.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_get_or_persist_is_abandoned() {
+        let cache = Arc::new(make_cache());
+        let key = "db-table-derived";
+
+        let cancelled = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            async move {
+                cache
+                    .get_or_persist("db", "table", key, 0, 10, || async {
+                        futures_util::future::pending::<()>().await;
+                        Ok::<_, DataFusionError>(make_stream(vec![1]).await)
+                    })
+                    .await
+            }
+        });
+        while !cache.in_flight.lock().persists.contains_key(key) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        cancelled.abort();
+        assert!(cancelled.await.unwrap_err().is_cancelled());
+
+        // Nothing is left behind holding on to the cache
+        assert!(cache.in_flight.lock().persists.is_empty());
+        assert_eq!(Arc::strong_count(&cache), 1);
+
+        // The next caller runs its own `produce`
+        let path = cache
+            .get_or_persist("db", "table", key, 0, 10, || async {
+                Ok::<_, DataFusionError>(make_stream(vec![1, 2, 3]).await)
+            })
+            .await
+            .unwrap();
+        assert_eq!(cache.get_parquet_file(path.as_ref()).unwrap().row_count, 3);
+    }
+
+    #[tokio::test]
+    async fn get_or_persist_path_in_other_table() {
+        let cache = Arc::new(make_cache());
+        let key = "db-table-derived";
+        cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1]).await,
+                Some(ObjPath::from(key)),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let produced = Arc::new(AtomicBool::new(false));
+        let err = cache
+            .get_or_persist("db", "other_table", key, 0, 10, {
+                let produced = Arc::clone(&produced);
+                move || async move {
+                    produced.store(true, Ordering::SeqCst);
+                    Ok::<_, DataFusionError>(make_stream(vec![1, 2]).await)
+                }
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::PathInOtherTable { .. }));
+        assert!(!produced.load(Ordering::SeqCst));
+        assert_eq!(cache.get_parquet_file(key).unwrap().row_count, 1);
+    }
+
+    /// Wait until `waiters` persists are waiting on the persist to `path`
+    /// that is in flight
+    async fn wait_for_in_flight_waiters(cache: &ParquetCache, path: &ObjPath, waiters: usize) {
+        // The in flight map holds a handle to the persist as well
+        while cache
+            .in_flight
+            .lock()
+            .persists
+            .get(path.as_ref())
+            .and_then(Shared::strong_count)
+            != Some(waiters + 1)
+        {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn replacing_a_file_is_not_read_safe() {
+        let cache = make_cache();
+        let path = ObjPath::from("db-table-replaced");
+        cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                Some(path.clone()),
+                None,
+            )
+            .await
+            .unwrap();
+
+        // A query planned against the file before it's replaced...
+        let planned = cache.get_parquet_file(path.as_ref()).unwrap();
+        cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream((0..100).collect()).await,
+                Some(path.clone()),
+                None,
+            )
+            .await
+            .unwrap();
+
+        // ...reads the new file under the old file's size
+        let bytes = cache
+            .object_store()
+            .get(&path)
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        assert_ne!(bytes.len() as u64, planned.size_bytes);
+        assert_eq!(
+            bytes.len() as u64,
+            cache.get_parquet_file(path.as_ref()).unwrap().size_bytes
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_persists_to_the_same_path_serialize_once() {
+        let cache = Arc::new(make_cache());
+        let path = ObjPath::from("db-table-shared");
+        let batches_read = Arc::new(AtomicUsize::new(0));
+
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int32, false)]));
+        let counted = |stream: SendableRecordBatchStream| -> SendableRecordBatchStream {
+            let batches_read = Arc::clone(&batches_read);
+            Box::pin(RecordBatchStreamAdapter::new(
+                Arc::clone(&schema),
+                stream.inspect(move |_| {
+                    batches_read.fetch_add(1, Ordering::SeqCst);
+                }),
+            ))
+        };
+
+        // The first persist's batch isn't sent until the second persist has
+        // started, so the second finds the first still in flight
+        let stream_builder = RecordBatchReceiverStreamBuilder::new(Arc::clone(&schema), 5);
+        let first_tx = stream_builder.tx();
+        let first = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            let path = path.clone();
+            let stream = counted(stream_builder.build());
+            async move {
+                cache
+                    .persist_parquet_file("db", "table", 0, 10, stream, Some(path), None)
+                    .await
+            }
+        });
+        while !cache.in_flight.lock().persists.contains_key(path.as_ref()) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let second = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            let path = path.clone();
+            let stream = counted(make_stream(vec![4, 5, 6]).await);
+            async move {
+                cache
+                    .persist_parquet_file("db", "table", 0, 10, stream, Some(path), None)
+                    .await
+            }
+        });
+        wait_for_in_flight_waiters(&cache, &path, 1).await;
+
+        let batch = RecordBatch::try_new(
+            Arc::clone(&schema),
+            vec![Arc::new(Int32Array::from(vec![1, 2, 3]))],
+        )
+        .unwrap();
+        first_tx.send(Ok(batch)).await.unwrap();
+        drop(first_tx);
+
+        assert_eq!(first.await.unwrap().unwrap(), path);
+        assert_eq!(second.await.unwrap().unwrap(), path);
+
+        // Only the first persist's batches were serialized
+        assert_eq!(batches_read.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            cache
+                .parquet_file_version("db", "table", path.as_ref())
+                .unwrap(),
+            1
+        );
+        assert!(cache.in_flight.lock().persists.is_empty());
+    }
+
+    #[tokio::test]
+    async fn conditional_persist_waiting_on_in_flight_persist_conflicts() {
+        let cache = Arc::new(make_cache());
+        let path = ObjPath::from("db-table-conditional");
+
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int32, false)]));
+        let stream_builder = RecordBatchReceiverStreamBuilder::new(Arc::clone(&schema), 5);
+        let first_tx = stream_builder.tx();
+        let first = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            let path = path.clone();
+            let stream = stream_builder.build();
+            async move {
+                cache
+                    .persist_parquet_file("db", "table", 0, 10, stream, Some(path), Some(0))
+                    .await
+            }
+        });
+        while !cache.in_flight.lock().persists.contains_key(path.as_ref()) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // Both persists expect there to be no file at the path yet
+        let second = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            let path = path.clone();
+            let stream = make_stream(vec![4, 5, 6]).await;
+            async move {
+                cache
+                    .persist_parquet_file("db", "table", 0, 10, stream, Some(path), Some(0))
+                    .await
+            }
+        });
+        wait_for_in_flight_waiters(&cache, &path, 1).await;
+
+        let batch = RecordBatch::try_new(
+            Arc::clone(&schema),
+            vec![Arc::new(Int32Array::from(vec![1, 2, 3]))],
+        )
+        .unwrap();
+        first_tx.send(Ok(batch)).await.unwrap();
+        drop(first_tx);
+
+        assert_eq!(first.await.unwrap().unwrap(), path);
+        assert!(matches!(
+            second.await.unwrap().unwrap_err(),
+            Error::VersionConflict {
+                expected: 0,
+                actual: 1
+            }
+        ));
+        assert_eq!(
+            cache
+                .parquet_file_version("db", "table", path.as_ref())
+                .unwrap(),
+            1
+        );
+        assert_eq!(cache.get_parquet_file(path.as_ref()).unwrap().row_count, 3);
+    }
+
+    #[tokio::test]
+    async fn persist_waiting_on_in_flight_persist_for_other_table() {
+        let cache = Arc::new(make_cache());
+        let path = ObjPath::from("db-table-shared");
+
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int32, false)]));
+        let stream_builder = RecordBatchReceiverStreamBuilder::new(Arc::clone(&schema), 5);
+        let first_tx = stream_builder.tx();
+        let first = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            let path = path.clone();
+            let stream = stream_builder.build();
+            async move {
+                cache
+                    .persist_parquet_file("db", "table", 0, 10, stream, Some(path), None)
+                    .await
+            }
+        });
+        while !cache.in_flight.lock().persists.contains_key(path.as_ref()) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let second = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            let path = path.clone();
+            let stream = make_stream(vec![4, 5, 6]).await;
+            async move {
+                cache
+                    .persist_parquet_file("db", "other_table", 0, 10, stream, Some(path), None)
+                    .await
+            }
+        });
+        wait_for_in_flight_waiters(&cache, &path, 1).await;
+
+        let batch = RecordBatch::try_new(
+            Arc::clone(&schema),
+            vec![Arc::new(Int32Array::from(vec![1, 2, 3]))],
+        )
+        .unwrap();
+        first_tx.send(Ok(batch)).await.unwrap();
+        drop(first_tx);
+
+        assert_eq!(first.await.unwrap().unwrap(), path);
+        assert!(matches!(
+            second.await.unwrap().unwrap_err(),
+            Error::PathInOtherTable { .. }
+        ));
+        assert!(cache.get_parquet_files("db", "other_table").is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_or_persist_retries_after_failed_persist() {
+        let cache = Arc::new(make_cache());
+        let path = ObjPath::from("db-table-derived");
+
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int32, false)]));
+        let stream_builder = RecordBatchReceiverStreamBuilder::new(Arc::clone(&schema), 5);
+        let first_tx = stream_builder.tx();
+        let first = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            let path = path.clone();
+            let stream = stream_builder.build();
+            async move {
+                cache
+                    .persist_parquet_file("db", "table", 0, 10, stream, Some(path), None)
+                    .await
+            }
+        });
+        while !cache.in_flight.lock().persists.contains_key(path.as_ref()) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let produced = Arc::new(AtomicUsize::new(0));
+        let second = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            let produced = Arc::clone(&produced);
+            async move {
+                cache
+                    .get_or_persist(
+                        "db",
+                        "table",
+                        "db-table-derived",
+                        0,
+                        10,
+                        move || async move {
+                            produced.fetch_add(1, Ordering::SeqCst);
+                            Ok::<_, DataFusionError>(make_stream(vec![1, 2, 3]).await)
+                        },
+                    )
+                    .await
+            }
+        });
+        wait_for_in_flight_waiters(&cache, &path, 1).await;
+
+        first_tx
+            .send(Err(DataFusionError::Execution("boom".to_string())))
+            .await
+            .unwrap();
+        drop(first_tx);
+        assert!(first.await.unwrap().is_err());
+
+        // The waiting caller produced the file itself
+        assert_eq!(second.await.unwrap().unwrap(), path);
+        assert_eq!(produced.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get_parquet_file(path.as_ref()).unwrap().row_count, 3);
+    }
+
+    #[tokio::test]
+    async fn failed_put_leaves_nothing_behind() {
+        let (cache, store) = make_cache_with_test_store();
+        let path = ObjPath::from("db-table-failed");
+
+        store.fail_puts.store(true, Ordering::SeqCst);
+        let err = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                Some(path.clone()),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ObjectStore(_)));
+        assert!(stored_paths(store.as_ref()).await.is_empty());
+        assert_eq!(cache.stats().file_count, 0);
+        assert_eq!(cache.current_size_bytes(), 0);
+        // The path is free to be persisted to again
+        assert!(cache.in_flight.lock().persists.is_empty());
+
+        store.fail_puts.store(false, Ordering::SeqCst);
+        cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                Some(path.clone()),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(stored_paths(store.as_ref()).await, vec![path]);
+    }
+
+    #[tokio::test]
+    async fn evicts_oldest_files_past_size_limit() {
+        // Every file is serialized from the same data so they're all the
+        // same size. Find out what that size is first.
+        let file_size = {
+            let cache = make_cache();
+            let path = cache
+                .persist_parquet_file(
+                    "db",
+                    "table",
+                    0,
+                    10,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            cache.load_parquet_file(path).await.unwrap().len() as u64
+        };
+
+        // Room for three files but not four
+        let mem_pool: Arc<dyn MemoryPool> = Arc::new(UnboundedMemoryPool::default());
+        let cache = ParquetCache::new(&mem_pool, file_size * 4 - 1);
+
+        let mut paths = Vec::new();
+        for i in 0..3 {
+            let path = ObjPath::from(format!("db-table-{i}"));
+            cache
+                .persist_parquet_file(
+                    "db",
+                    "table",
+                    0,
+                    10,
+                    make_stream(vec![1, 2, 3]).await,
+                    Some(path.clone()),
+                    None,
+                )
+                .await
+                .unwrap();
+            paths.push(path);
+        }
+        assert_eq!(cache.current_size_bytes(), file_size * 3);
+
+        // The fourth file pushes out the first one that was inserted
+        let path = ObjPath::from("db-other_table-3");
+        cache
+            .persist_parquet_file(
+                "db",
+                "other_table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                Some(path.clone()),
+                None,
+            )
+            .await
+            .unwrap();
+        paths.push(path);
+
+        assert_eq!(cache.current_size_bytes(), file_size * 3);
+        assert!(cache.current_size_bytes() <= file_size * 4 - 1);
+        let mut cached = cache
+            .get_parquet_files("db", "table")
+            .into_iter()
+            .chain(cache.get_parquet_files("db", "other_table"))
+            .map(|file| file.path)
+            .collect::<Vec<_>>();
+        cached.sort();
+        assert_eq!(cached, vec!["db-other_table-3", "db-table-1", "db-table-2"]);
+
+        // The evicted file is gone from the object store too
+        assert!(cache.load_parquet_file(paths[0].clone()).await.is_err());
+        for path in &paths[1..] {
+            cache.load_parquet_file(path.clone()).await.unwrap();
+        }
+
+        // A file larger than the whole cache is rejected rather than
+        // evicting everything else to make room it can't fit in anyway
+        let err = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream((0..10_000).collect()).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::FileTooLarge { max_file_bytes, .. }
+            if max_file_bytes == file_size * 4 - 1));
+        assert_eq!(cache.current_size_bytes(), file_size * 3);
+        for path in &paths[1..] {
+            assert!(cache.get_parquet_file(path.as_ref()).is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn on_evict() {
+        let file_size = {
+            let cache = make_cache();
+            let path = cache
+                .persist_parquet_file(
+                    "db",
+                    "table",
+                    0,
+                    10,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            cache.get_parquet_file(path.as_ref()).unwrap().size_bytes
+        };
+
+        // Room for two files but not three
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mem_pool: Arc<dyn MemoryPool> = Arc::new(UnboundedMemoryPool::default());
+        let cache = ParquetCache::new(&mem_pool, file_size * 3 - 1).with_on_evict({
+            let evicted = Arc::clone(&evicted);
+            Arc::new(move |file: &ParquetFile| evicted.lock().push(file.clone()))
+        });
+
+        let mut files = Vec::new();
+        for min_time in [0, 10] {
+            let path = cache
+                .persist_parquet_file(
+                    "db",
+                    "table",
+                    min_time,
+                    min_time + 9,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            files.push(cache.get_parquet_file(path.as_ref()).unwrap());
+        }
+        assert!(evicted.lock().is_empty());
+
+        cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                20,
+                29,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(*evicted.lock(), vec![files[0].clone()]);
+    }
+
+    #[tokio::test]
+    async fn subscribe() {
+        let cache = make_cache();
+        let mut events = cache.subscribe();
+
+        let path = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let file = cache.get_parquet_file(path.as_ref()).unwrap();
+        assert_eq!(events.recv().await.unwrap(), CacheEvent::Inserted(file));
+
+        cache.remove_parquet_file(path.clone()).await.unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            CacheEvent::Removed(path.to_string())
+        );
+
+        // Removing a file that's already gone isn't an event
+        cache.remove_parquet_file(path).await.unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty)
+        ));
+
+        // Clearing a database removes each of its files
+        let mut paths = Vec::new();
+        for _ in 0..2 {
+            let path = cache
+                .persist_parquet_file(
+                    "db",
+                    "table",
+                    0,
+                    10,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(
+                events.recv().await.unwrap(),
+                CacheEvent::Inserted(_)
+            ));
+            paths.push(path.to_string());
+        }
+        cache.clear_database("db").await.unwrap();
+        let mut removed = Vec::new();
+        for _ in 0..2 {
+            match events.recv().await.unwrap() {
+                CacheEvent::Removed(path) => removed.push(path),
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+        removed.sort();
+        paths.sort();
+        assert_eq!(removed, paths);
+        assert!(matches!(
+            events.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty)
+        ));
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_file() {
+        let file_size = {
+            let cache = make_cache();
+            let path = cache
+                .persist_parquet_file(
+                    "db",
+                    "table",
+                    0,
+                    10,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            cache.load_parquet_file(path).await.unwrap().len() as u64
+        };
+
+        // Room for three files but not four
+        let mem_pool: Arc<dyn MemoryPool> = Arc::new(UnboundedMemoryPool::default());
+        let cache = ParquetCache::new(&mem_pool, file_size * 4 - 1);
+
+        for i in 0..3 {
+            cache
+                .persist_parquet_file(
+                    "db",
+                    "table",
+                    0,
+                    10,
+                    make_stream(vec![1, 2, 3]).await,
+                    Some(ObjPath::from(format!("db-table-{i}"))),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        // Use the oldest file so that the second file is now the least
+        // recently used
+        cache
+            .load_parquet_file(ObjPath::from("db-table-0"))
+            .await
+            .unwrap();
+
+        cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                Some(ObjPath::from("db-table-3")),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let mut cached = cache
+            .get_parquet_files("db", "table")
+            .into_iter()
+            .map(|file| file.path)
+            .collect::<Vec<_>>();
+        cached.sort();
+        assert_eq!(cached, vec!["db-table-0", "db-table-2", "db-table-3"]);
+    }
+
+    #[tokio::test]
+    async fn db_table_uuid_path_scheme() {
+        let cache = make_cache().with_path_scheme(PathScheme::DbTableUuid);
+
+        let path = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(path.as_ref().starts_with("db-table-"));
+        assert_eq!(cache.get_parquet_files("db", "table").len(), 1);
+
+        cache.remove_parquet_file(path.clone()).await.unwrap();
+        assert!(cache.get_parquet_files("db", "table").is_empty());
+        assert!(cache.load_parquet_file(path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn hierarchical_path_scheme() {
+        let cache = make_cache().with_path_scheme(PathScheme::Hierarchical);
+
+        let mut paths = Vec::new();
+        for table_name in ["cpu", "cpu", "mem"] {
+            let path = cache
+                .persist_parquet_file(
+                    "db",
+                    table_name,
+                    0,
+                    10,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            paths.push(path);
+        }
+
+        let parts: Vec<_> = paths[0]
+            .parts()
+            .map(|part| part.as_ref().to_string())
+            .collect();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0], "db");
+        assert_eq!(parts[1], "cpu");
+        assert!(parts[2].ends_with(".parquet"));
+
+        // The files for a table can be listed by prefix
+        let listed: Vec<_> = cache
+            .object_store()
+            .list(Some(&ObjPath::from("db/cpu")))
+            .map(|meta| meta.unwrap().location)
+            .collect()
+            .await;
+        assert_eq!(listed.len(), 2);
+        assert!(listed.contains(&paths[0]) && listed.contains(&paths[1]));
+
+        cache.remove_parquet_file(paths[0].clone()).await.unwrap();
+        assert_eq!(cache.get_parquet_files("db", "cpu").len(), 1);
+        assert_eq!(cache.get_parquet_files("db", "mem").len(), 1);
+        assert!(cache.load_parquet_file(paths[0].clone()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn load_parquet_stream() {
+        let cache = make_cache();
+
+        let path = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream((0..10_000).collect()).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        let streamed: Vec<Bytes> = cache
+            .load_parquet_stream(path.clone())
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let streamed: Vec<u8> = streamed.into_iter().flatten().collect();
+        let loaded = cache.load_parquet_file(path).await.unwrap();
+        assert_eq!(streamed, loaded.to_vec());
+        assert_eq!(cache.stats().hits, 2);
+
+        assert!(cache
+            .load_parquet_stream(ObjPath::from("db-table-missing"))
+            .await
+            .is_err());
+        assert_eq!(cache.stats().misses, 1);
+    }
+
+    #[tokio::test]
+    async fn reserved_bytes() {
+        let mem_pool: Arc<dyn MemoryPool> = Arc::new(UnboundedMemoryPool::default());
+        let cache = Arc::new(ParquetCache::new(&mem_pool, DEFAULT_MAX_SIZE_BYTES));
+        assert_eq!(cache.reserved_bytes(), 0);
+
+        // Hold the stream open after its first batch so that the persist is
+        // part way through serializing it
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int32, false)]));
+        let stream_builder = RecordBatchReceiverStreamBuilder::new(Arc::clone(&schema), 5);
+        let tx = stream_builder.tx();
+        let persist = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            let stream = stream_builder.build();
+            async move {
+                cache
+                    .persist_parquet_file("db", "table", 0, 10, stream, None, None)
+                    .await
+            }
+        });
+
+        let batch = RecordBatch::try_new(
+            Arc::clone(&schema),
+            vec![Arc::new(Int32Array::from((0..1000).collect::<Vec<_>>()))],
+        )
+        .unwrap();
+        tx.send(Ok(batch)).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while cache.reserved_bytes() == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("the persist reserved memory");
+        assert_eq!(cache.pool_reserved_bytes(), cache.reserved_bytes());
+
+        drop(tx);
+        persist.await.unwrap().unwrap();
+        assert_eq!(cache.reserved_bytes(), 0);
+        assert_eq!(mem_pool.reserved(), 0);
+    }
+
+    #[tokio::test]
+    async fn table_compression() {
+        let cache = make_cache().with_compression(ParquetCompression::Uncompressed);
+        cache.set_table_compression("db", "cold", ParquetCompression::Zstd);
+        assert_eq!(
+            cache.table_compression("db", "hot"),
+            ParquetCompression::Uncompressed
+        );
+        assert_eq!(
+            cache.table_compression("db", "cold"),
+            ParquetCompression::Zstd
+        );
+
+        let mut sizes = Vec::new();
+        for table_name in ["hot", "cold"] {
+            let path = cache
+                .persist_parquet_file(
+                    "db",
+                    table_name,
+                    0,
+                    10,
+                    make_stream((0..10_000).collect()).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            sizes.push(cache.get_parquet_file(path.as_ref()).unwrap().size_bytes);
+        }
+        assert!(sizes[1] < sizes[0], "{sizes:?}");
+    }
+
+    #[tokio::test]
+    async fn checksums() {
+        for verify_checksums in [true, false] {
+            let cache = if verify_checksums {
+                make_cache().with_checksums()
+            } else {
+                make_cache()
+            };
+
+            let path = cache
+                .persist_parquet_file(
+                    "db",
+                    "table",
+                    0,
+                    10,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            let bytes = cache.load_parquet_file(path.clone()).await.unwrap();
+
+            // Flip a bit of the file in the object store behind the cache's back
+            let mut corrupted = bytes.to_vec();
+            corrupted[bytes.len() / 2] ^= 1;
+            cache
+                .object_store()
+                .put(&path, Bytes::from(corrupted))
+                .await
+                .unwrap();
+
+            let result = cache.load_parquet_file(path.clone()).await;
+            if verify_checksums {
+                match result {
+                    Err(Error::Corrupted {
+                        path: corrupted_path,
+                    }) => assert_eq!(corrupted_path, path.to_string()),
+                    other => panic!("expected the load to fail as corrupted, got {other:?}"),
+                }
+            } else {
+                assert!(result.is_ok());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn table_row_budget() {
+        let cache = make_cache();
+        cache.set_table_row_budget("db", "cpu", 6);
+
+        // Persisted out of time order, each with 3 rows
+        let mut paths = HashMap::new();
+        for (min_time, max_time) in [(20, 29), (0, 9), (10, 19)] {
+            let path = cache
+                .persist_parquet_file(
+                    "db",
+                    "cpu",
+                    min_time,
+                    max_time,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            paths.insert(min_time, path);
+        }
+        // Tables without a budget aren't limited
+        for _ in 0..3 {
+            cache
+                .persist_parquet_file(
+                    "db",
+                    "mem",
+                    0,
+                    9,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        // The file covering the oldest time range went first
+        let mut min_times: Vec<_> = cache
+            .get_parquet_files("db", "cpu")
+            .into_iter()
+            .map(|file| file.min_time)
+            .collect();
+        min_times.sort();
+        assert_eq!(min_times, vec![10, 20]);
+        assert!(cache.load_parquet_file(paths[&0].clone()).await.is_err());
+        assert_eq!(cache.get_parquet_files("db", "mem").len(), 3);
+
+        // A newer file evicts the now oldest file
+        cache
+            .persist_parquet_file(
+                "db",
+                "cpu",
+                30,
+                39,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let mut min_times: Vec<_> = cache
+            .get_parquet_files("db", "cpu")
+            .into_iter()
+            .map(|file| file.min_time)
+            .collect();
+        min_times.sort();
+        assert_eq!(min_times, vec![20, 30]);
+        assert_eq!(
+            cache.current_size_bytes(),
+            cache.database_bytes("db"),
+            "evicted files no longer count towards the cache's size"
+        );
+    }
+
+    #[tokio::test]
+    async fn table_row_budget_spills_to_spill_store() {
+        let spill_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let cache = make_cache().with_spill_store(Arc::clone(&spill_store));
+        cache.set_table_row_budget("db", "cpu", 3);
+
+        let mut paths = Vec::new();
+        for (min_time, max_time) in [(0, 9), (10, 19)] {
+            let path = cache
+                .persist_parquet_file(
+                    "db",
+                    "cpu",
+                    min_time,
+                    max_time,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            paths.push(path);
+        }
+
+        // The older file was moved out of the cache to the spill store
+        assert_eq!(
+            cache.get_parquet_files("db", "cpu"),
+            vec![cache.get_parquet_file(paths[1].as_ref()).unwrap()]
+        );
+        assert_eq!(
+            stored_paths(cache.object_store().as_ref()).await,
+            vec![paths[1].clone()]
+        );
+        assert_eq!(
+            stored_paths(spill_store.as_ref()).await,
+            vec![paths[0].clone()]
+        );
+    }
+
+    #[tokio::test]
+    async fn table_last_persist() {
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let cache = make_cache().with_time_provider(Arc::<MockProvider>::clone(&time_provider));
+        assert!(cache.table_last_persist("db", "cpu").is_none());
+
+        let persist = |table_name| {
+            let cache = &cache;
+            async move {
+                cache
+                    .persist_parquet_file(
+                        "db",
+                        table_name,
+                        0,
+                        10,
+                        make_stream(vec![1, 2, 3]).await,
+                        None,
+                        None,
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        time_provider.set(Time::from_timestamp_nanos(100 * 1_000_000_000));
+        persist("cpu").await;
+        time_provider.set(Time::from_timestamp_nanos(200 * 1_000_000_000));
+        let mem_path = persist("mem").await;
+
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(cache.table_last_persist("db", "cpu"), Some(at(100)));
+        assert_eq!(cache.table_last_persist("db", "mem"), Some(at(200)));
+
+        time_provider.set(Time::from_timestamp_nanos(300 * 1_000_000_000));
+        persist("cpu").await;
+        assert_eq!(cache.table_last_persist("db", "cpu"), Some(at(300)));
+
+        // Removing the table's files doesn't change when it was last persisted
+        cache.remove_parquet_file(mem_path).await.unwrap();
+        assert_eq!(cache.table_last_persist("db", "mem"), Some(at(200)));
+
+        cache.clear_database("db").await.unwrap();
+        assert!(cache.table_last_persist("db", "cpu").is_none());
+    }
+
+    #[tokio::test]
+    async fn persist_parquet_files() {
+        let cache = make_cache();
+
+        let batches = vec![
+            (0, 9, make_stream(vec![1]).await),
+            (10, 19, make_stream(vec![1, 2]).await),
+            (20, 29, make_stream(vec![1, 2, 3]).await),
+        ];
+        let paths = cache
+            .persist_parquet_files("db", "table", batches)
+            .await
+            .unwrap();
+        assert_eq!(paths.len(), 3);
+
+        let mut files = cache.get_parquet_files("db", "table");
+        files.sort_by_key(|file| file.min_time);
+        assert_eq!(files.len(), 3);
+        for ((file, path), (min_time, row_count)) in
+            files.iter().zip(&paths).zip([(0, 1), (10, 2), (20, 3)])
+        {
+            assert_eq!(file.path, path.to_string());
+            assert_eq!(file.min_time, min_time);
+            assert_eq!(file.row_count, row_count);
+        }
+        assert_eq!(
+            cache.current_size_bytes(),
+            files.iter().map(|file| file.size_bytes).sum::<u64>()
+        );
+        assert!(cache.table_last_persist("db", "table").is_some());
+    }
+
+    #[tokio::test]
+    async fn persist_parquet_files_failure_caches_nothing() {
+        let (cache, store) = make_cache_with_test_store();
+
+        // The first stream persists but the second holds no rows, which
+        // fails its serialization
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int32, false)]));
+        let empty: SendableRecordBatchStream = Box::pin(RecordBatchStreamAdapter::new(
+            Arc::clone(&schema),
+            futures_util::stream::empty::<Result<RecordBatch, DataFusionError>>(),
+        ));
+        let batches = vec![(0, 9, make_stream(vec![1]).await), (10, 19, empty)];
+        assert!(cache
+            .persist_parquet_files("db", "table", batches)
+            .await
+            .is_err());
+
+        assert!(stored_paths(store.as_ref()).await.is_empty());
+        assert!(cache.get_parquet_files("db", "table").is_empty());
+        assert_eq!(cache.current_size_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn max_file_bytes() {
+        let small_stream = || make_stream(vec![1, 2, 3]);
+        let large_stream = || make_stream((0..10_000).collect());
+
+        // Find out how big the small file is to set the limit just above it
+        let small_size = {
+            let cache = make_cache();
+            let path = cache
+                .persist_parquet_file("db", "table", 0, 10, small_stream().await, None, None)
+                .await
+                .unwrap();
+            cache.get_parquet_file(path.as_ref()).unwrap().size_bytes
+        };
+
+        let cache = make_cache().with_max_file_bytes(small_size, OversizedFilePolicy::Reject);
+        cache
+            .persist_parquet_file("db", "table", 0, 10, small_stream().await, None, None)
+            .await
+            .unwrap();
+        let err = cache
+            .persist_parquet_file("db", "table", 0, 10, large_stream().await, None, None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::FileTooLarge { size_bytes, max_file_bytes }
+                if size_bytes > small_size && max_file_bytes == small_size)
+        );
+        assert_eq!(cache.get_parquet_files("db", "table").len(), 1);
+        assert_eq!(stored_paths(cache.object_store().as_ref()).await.len(), 1);
+
+        let uncached_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let cache = make_cache().with_max_file_bytes(
+            small_size,
+            OversizedFilePolicy::StoreUncached(Arc::clone(&uncached_store)),
+        );
+        let small_path = cache
+            .persist_parquet_file("db", "table", 0, 10, small_stream().await, None, None)
+            .await
+            .unwrap();
+        let large_path = cache
+            .persist_parquet_file("db", "table", 0, 10, large_stream().await, None, None)
+            .await
+            .unwrap();
+
+        // The large file was stored but isn't cached
+        assert_eq!(
+            cache.get_parquet_files("db", "table"),
+            vec![cache.get_parquet_file(small_path.as_ref()).unwrap()]
+        );
+        assert_eq!(cache.current_size_bytes(), small_size);
+        assert!(cache.get_parquet_file(large_path.as_ref()).is_none());
+        assert_eq!(
+            stored_paths(cache.object_store().as_ref()).await,
+            vec![small_path.clone()]
+        );
+        assert_eq!(
+            stored_paths(uncached_store.as_ref()).await,
+            vec![large_path]
+        );
+
+        // A stale version is still a conflict, and leaves nothing behind
+        let err = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                large_stream().await,
+                Some(small_path.clone()),
+                Some(2),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::VersionConflict {
+                expected: 2,
+                actual: 1
+            }
+        ));
+        assert_eq!(stored_paths(uncached_store.as_ref()).await.len(), 1);
+
+        // Overwriting a cached file with one that's too large drops the
+        // cached file rather than leaving its stale metadata behind
+        cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                large_stream().await,
+                Some(small_path.clone()),
+                Some(1),
+            )
+            .await
+            .unwrap();
+        assert!(cache.get_parquet_file(small_path.as_ref()).is_none());
+        assert_eq!(cache.current_size_bytes(), 0);
+        assert!(stored_paths(cache.object_store().as_ref()).await.is_empty());
+        assert_eq!(stored_paths(uncached_store.as_ref()).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn uuid_generator() {
+        let next_id = AtomicU64::new(1);
+        let cache = make_cache()
+            .with_path_scheme(PathScheme::Hierarchical)
+            .with_uuid_generator(Arc::new(move || {
+                uuid::Uuid::from_u128(next_id.fetch_add(1, Ordering::SeqCst).into())
+            }));
+
+        let path = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            path,
+            ObjPath::from("db/table/00000000-0000-0000-0000-000000000001.parquet")
+        );
+
+        let paths = cache
+            .persist_parquet_files("db", "table", vec![(0, 10, make_stream(vec![1]).await)])
+            .await
+            .unwrap();
+        assert_eq!(
+            paths,
+            vec![ObjPath::from(
+                "db/table/00000000-0000-0000-0000-000000000002.parquet"
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn names_with_dashes() {
+        let cache = make_cache();
+
+        let path = cache
+            .persist_parquet_file(
+                "my-db",
+                "my-metrics-2024",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(cache.get_parquet_files("my-db", "my-metrics-2024").len(), 1);
+
+        cache.remove_parquet_file(path.clone()).await.unwrap();
+        assert!(cache
+            .get_parquet_files("my-db", "my-metrics-2024")
+            .is_empty());
+        assert_eq!(cache.current_size_bytes(), 0);
+        assert!(cache.load_parquet_file(path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn snapshot() {
+        let cache = make_cache();
+        assert_eq!(cache.snapshot(), CacheSnapshot::default());
+
+        for (db_name, table_name, min_time) in
+            [("db", "cpu", 0), ("db", "cpu", 10), ("db", "mem", 0)]
+        {
+            cache
+                .persist_parquet_file(
+                    db_name,
+                    table_name,
+                    min_time,
+                    min_time + 9,
+                    make_stream(vec![1, 2, 3]).await,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        let snapshot = cache.snapshot();
+
+        // Changes to the cache after the snapshot was taken aren't seen in it
+        cache
+            .persist_parquet_file(
+                "db",
+                "cpu",
+                20,
+                29,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        cache
+            .persist_parquet_file(
+                "db2",
+                "cpu",
+                0,
+                9,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        cache.clear_database("db").await.unwrap();
+
+        assert_eq!(snapshot.databases(), vec!["db"]);
+        assert_eq!(snapshot.tables("db"), vec!["cpu", "mem"]);
+        assert_eq!(snapshot.parquet_files("db", "cpu").len(), 2);
+        assert_eq!(snapshot.parquet_files("db", "mem").len(), 1);
+        assert!(snapshot.parquet_files("db", "disk").is_empty());
+
+        let in_range = snapshot.parquet_files_in_range("db", "cpu", 5, 9);
+        assert_eq!(in_range.len(), 1);
+        assert_eq!(in_range[0].min_time, 0);
+
+        assert_eq!(cache.snapshot().databases(), vec!["db2"]);
+    }
+
+    #[tokio::test]
+    async fn get_parquet_files_in_range() {
+        let cache = make_cache();
+
+        for (min_time, max_time) in [(0, 9), (10, 19), (20, 29)] {
+            cache
+                .persist_parquet_file(
+                    "db",
+                    "table",
+                    min_time,
+                    max_time,
+                    make_stream(vec![1, 2, 3]).await,
+                    Some(ObjPath::from(format!("db-table-{min_time}"))),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        let in_range = |min_time, max_time| {
+            let mut paths = cache
+                .get_parquet_files_in_range("db", "table", min_time, max_time)
+                .into_iter()
+                .map(|file| file.path)
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(in_range(12, 15), vec!["db-table-10"]);
+        assert_eq!(
+            in_range(5, 25),
+            vec!["db-table-0", "db-table-10", "db-table-20"]
+        );
+        // Ranges that only touch a file at its boundaries still include it
+        assert_eq!(in_range(19, 20), vec!["db-table-10", "db-table-20"]);
+        assert_eq!(in_range(-10, 0), vec!["db-table-0"]);
+        assert_eq!(in_range(29, 40), vec!["db-table-20"]);
+        assert!(in_range(30, 40).is_empty());
+        assert!(cache
+            .get_parquet_files_in_range("db", "other_table", 0, 40)
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn persist_load_and_remove_with_local_disk() {
+        let local_disk =
+            LocalFileSystem::new_with_prefix(test_helpers::tmp_dir().unwrap()).unwrap();
+        let object_store: Arc<dyn ObjectStore> = Arc::new(local_disk);
+        let mem_pool: Arc<dyn MemoryPool> = Arc::new(UnboundedMemoryPool::default());
+        let cache = ParquetCache::with_object_store(
+            Arc::clone(&object_store),
+            &mem_pool,
+            DEFAULT_MAX_SIZE_BYTES,
+        );
+
+        let path = cache
+            .persist_parquet_file(
+                "db",
+                "table",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        // The file was written to the backing store
+        let meta = object_store.head(&path).await.unwrap();
+        let bytes = cache.load_parquet_file(path.clone()).await.unwrap();
+        assert_eq!(bytes.len(), meta.size);
+        assert_eq!(
+            cache.get_parquet_files("db", "table")[0].size_bytes,
+            meta.size as u64
+        );
+
+        cache.remove_parquet_file(path.clone()).await.unwrap();
+        assert!(object_store.head(&path).await.is_err());
+        assert!(cache.load_parquet_file(path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn load_metadata_from_store() {
+        let local_disk =
+            LocalFileSystem::new_with_prefix(test_helpers::tmp_dir().unwrap()).unwrap();
+        let object_store: Arc<dyn ObjectStore> = Arc::new(local_disk);
+        let mem_pool: Arc<dyn MemoryPool> = Arc::new(UnboundedMemoryPool::default());
+
+        // Seed the store with files left behind by an earlier run
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            TIME_COLUMN_NAME,
+            DataType::Timestamp(TimeUnit::Nanosecond, None),
+            false,
+        )]));
+        let seeded = [
+            ("db/cpu/1.parquet", vec![10, 20, 30]),
+            ("db/cpu/2.parquet", vec![5, 15]),
+            ("db/mem/1.parquet", vec![100]),
+        ];
+        for (path, times) in seeded {
+            let batch = RecordBatch::try_new(
+                Arc::clone(&schema),
+                vec![Arc::new(TimestampNanosecondArray::from(times))],
+            )
+            .unwrap();
+            let stream: SendableRecordBatchStream = Box::pin(RecordBatchStreamAdapter::new(
+                Arc::clone(&schema),
+                futures_util::stream::iter([Ok::<_, DataFusionError>(batch)]),
+            ));
+            let parquet =
+                serialize_to_parquet(Arc::clone(&mem_pool), stream, ParquetCompression::default())
+                    .await
+                    .unwrap();
+            object_store
+                .put(&ObjPath::from(path), parquet.bytes)
+                .await
+                .unwrap();
+        }
+        object_store
+            .put(&ObjPath::from("db/cpu/README"), Bytes::from("not parquet"))
+            .await
+            .unwrap();
+
+        let cache = ParquetCache::with_object_store(
+            Arc::clone(&object_store),
+            &mem_pool,
+            DEFAULT_MAX_SIZE_BYTES,
+        );
+        let indexed = cache
+            .load_metadata_from_store("db", "cpu", &ObjPath::from("db/cpu"))
+            .await
+            .unwrap();
+        assert_eq!(indexed, 2);
+
+        let mut files = cache.get_parquet_files("db", "cpu");
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, "db/cpu/1.parquet");
+        assert_eq!(files[0].row_count, 3);
+        assert_eq!((files[0].min_time, files[0].max_time), (10, 30));
+        assert_eq!(files[1].path, "db/cpu/2.parquet");
+        assert_eq!(files[1].row_count, 2);
+        assert_eq!((files[1].min_time, files[1].max_time), (5, 15));
+        assert_eq!(
+            cache.current_size_bytes(),
+            files.iter().map(|file| file.size_bytes).sum::<u64>()
+        );
+        assert!(cache.get_parquet_files("db", "mem").is_empty());
+
+        // Warming again doesn't index the files twice
+        let indexed = cache
+            .load_metadata_from_store("db", "cpu", &ObjPath::from("db/cpu"))
+            .await
+            .unwrap();
+        assert_eq!(indexed, 0);
+        assert_eq!(cache.get_parquet_files("db", "cpu").len(), 2);
+
+        // A cache too small for every file indexes what fits without evicting,
+        // which would delete files from the store it's warming from
+        let max_size_bytes = files.iter().map(|file| file.size_bytes).max().unwrap();
+        let cache =
+            ParquetCache::with_object_store(Arc::clone(&object_store), &mem_pool, max_size_bytes);
+        let indexed = cache
+            .load_metadata_from_store("db", "cpu", &ObjPath::from("db/cpu"))
+            .await
+            .unwrap();
+        assert_eq!(indexed, 1);
+        assert!(cache.current_size_bytes() <= max_size_bytes);
+        assert_eq!(stored_paths(object_store.as_ref()).await.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let cache = make_cache();
+        assert_eq!(cache.stats(), CacheStats::default());
+
+        let path = cache
+            .persist_parquet_file(
+                "db_one",
+                "cpu",
+                0,
+                10,
+                make_stream(vec![1, 2, 3]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        cache
+            .persist_parquet_file(
+                "db_one",
+                "mem",
+                0,
+                10,
+                make_stream(vec![1]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        cache
+            .persist_parquet_file(
+                "db_two",
+                "cpu",
+                0,
+                10,
+                make_stream(vec![1]).await,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        cache.load_parquet_file(path.clone()).await.unwrap();
+        cache.load_parquet_file(path).await.unwrap();
+        assert!(cache
+            .load_parquet_file(ObjPath::from("not-a-file"))
+            .await
+            .is_err());
+
+        let stats = cache.stats();
+        assert_eq!(stats.file_count, 3);
+        assert_eq!(stats.size_bytes, cache.current_size_bytes());
+        assert_eq!(
+            stats.database_file_counts,
+            HashMap::from([("db_one".to_string(), 2), ("db_two".to_string(), 1)])
+        );
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 1);
+    }
+}
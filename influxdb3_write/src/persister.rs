@@ -72,36 +72,53 @@ impl PersisterImpl {
         &self,
         batches: SendableRecordBatchStream,
     ) -> Result<ParquetBytes> {
-        // The ArrowWriter::write() call will return an error if any subsequent
-        // batch does not match this schema, enforcing schema uniformity.
-        let schema = batches.schema();
-
-        let stream = batches;
-        let mut bytes = Vec::new();
-        pin_mut!(stream);
-
-        // Construct the arrow serializer with the metadata as part of the parquet
-        // file properties.
-        let mut writer = TrackedMemoryArrowWriter::try_new(
-            &mut bytes,
-            Arc::clone(&schema),
-            self.mem_pool.clone(),
-        )?;
-
-        while let Some(batch) = stream.try_next().await? {
-            writer.write(batch)?;
-        }
+        serialize_to_parquet(
+            Arc::clone(&self.mem_pool),
+            batches,
+            ParquetCompression::default(),
+        )
+        .await
+    }
+}
 
-        let writer_meta = writer.close()?;
-        if writer_meta.num_rows == 0 {
-            return Err(Error::NoRows);
-        }
+/// Serialize the given stream of [`RecordBatch`]es into parquet compressed
+/// with `compression`, tracking the memory used by the writer in the given
+/// [`MemoryPool`].
+pub async fn serialize_to_parquet(
+    mem_pool: Arc<dyn MemoryPool>,
+    batches: SendableRecordBatchStream,
+    compression: ParquetCompression,
+) -> Result<ParquetBytes> {
+    // The ArrowWriter::write() call will return an error if any subsequent
+    // batch does not match this schema, enforcing schema uniformity.
+    let schema = batches.schema();
+
+    let stream = batches;
+    let mut bytes = Vec::new();
+    pin_mut!(stream);
+
+    // Construct the arrow serializer with the metadata as part of the parquet
+    // file properties.
+    let mut writer = TrackedMemoryArrowWriter::try_new_with_compression(
+        &mut bytes,
+        Arc::clone(&schema),
+        mem_pool,
+        compression,
+    )?;
+
+    while let Some(batch) = stream.try_next().await? {
+        writer.write(batch)?;
+    }
 
-        Ok(ParquetBytes {
-            meta_data: writer_meta,
-            bytes: Bytes::from(bytes),
-        })
+    let writer_meta = writer.close()?;
+    if writer_meta.num_rows == 0 {
+        return Err(Error::NoRows);
     }
+
+    Ok(ParquetBytes {
+        meta_data: writer_meta,
+        bytes: Bytes::from(bytes),
+    })
 }
 
 #[async_trait]
@@ -266,6 +283,28 @@ pub struct ParquetBytes {
     pub meta_data: FileMetaData,
 }
 
+/// The compression codec used for the pages of a parquet file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParquetCompression {
+    /// ZSTD at its default level, which trades speed for a smaller file
+    #[default]
+    Zstd,
+    /// SNAPPY, which is faster to read and write than ZSTD but compresses less
+    Snappy,
+    /// No compression at all
+    Uncompressed,
+}
+
+impl From<ParquetCompression> for Compression {
+    fn from(compression: ParquetCompression) -> Self {
+        match compression {
+            ParquetCompression::Zstd => Self::ZSTD(Default::default()),
+            ParquetCompression::Snappy => Self::SNAPPY,
+            ParquetCompression::Uncompressed => Self::UNCOMPRESSED,
+        }
+    }
+}
+
 /// Wraps an [`ArrowWriter`] to track its buffered memory in a
 /// DataFusion [`MemoryPool`]
 #[derive(Debug)]
@@ -282,8 +321,19 @@ pub const ROW_GROUP_WRITE_SIZE: usize = 1024 * 1024;
 impl<W: Write + Send> TrackedMemoryArrowWriter<W> {
     /// create a new `TrackedMemoryArrowWriter<`
     pub fn try_new(sink: W, schema: SchemaRef, mem_pool: Arc<dyn MemoryPool>) -> Result<Self> {
+        Self::try_new_with_compression(sink, schema, mem_pool, ParquetCompression::default())
+    }
+
+    /// create a new `TrackedMemoryArrowWriter` that compresses with the
+    /// given codec
+    pub fn try_new_with_compression(
+        sink: W,
+        schema: SchemaRef,
+        mem_pool: Arc<dyn MemoryPool>,
+        compression: ParquetCompression,
+    ) -> Result<Self> {
         let props = WriterProperties::builder()
-            .set_compression(Compression::ZSTD(Default::default()))
+            .set_compression(compression.into())
             .set_max_row_group_size(ROW_GROUP_WRITE_SIZE)
             .build();
         let inner = ArrowWriter::try_new(sink, schema, Some(props))?;
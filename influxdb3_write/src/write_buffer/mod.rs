@@ -6,6 +6,7 @@ mod loader;
 mod segment_state;
 mod table_buffer;
 
+use crate::cache::{ParquetCache, DEFAULT_MAX_SIZE_BYTES};
 use crate::catalog::{
     Catalog, DatabaseSchema, TableDefinition, SERIES_ID_COLUMN_NAME, TIME_COLUMN_NAME,
 };
@@ -13,7 +14,6 @@ use crate::chunk::ParquetChunk;
 use crate::write_buffer::flusher::WriteBufferFlusher;
 use crate::write_buffer::loader::load_starting_state;
 use crate::write_buffer::segment_state::{run_buffer_segment_persist_and_cleanup, SegmentState};
-use crate::DatabaseTables;
 use crate::{
     persister, BufferSegment, BufferedWriteRequest, Bufferer, ChunkContainer, LpWriteOp, Persister,
     Precision, SegmentDuration, SegmentId, SequenceNumber, Wal, WalOp, WriteBuffer, WriteLineError,
@@ -24,15 +24,14 @@ use data_types::{
 };
 use datafusion::common::DataFusionError;
 use datafusion::execution::context::SessionState;
+use datafusion::execution::memory_pool::{MemoryPool, UnboundedMemoryPool};
 use datafusion::logical_expr::Expr;
 use influxdb_line_protocol::{parse_lines, FieldValue, ParsedLine, Series, TagSet};
 use iox_query::chunk_statistics::create_chunk_statistics;
 use iox_query::QueryChunk;
 use iox_time::{Time, TimeProvider};
-use object_store::memory::InMemory;
 use object_store::path::Path as ObjPath;
 use object_store::ObjectMeta;
-use object_store::ObjectStore;
 use observability_deps::tracing::{debug, error};
 use parking_lot::{Mutex, RwLock};
 use parquet_file::storage::ParquetExecInput;
@@ -40,7 +39,6 @@ use sha2::Digest;
 use sha2::Sha256;
 use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
-use std::ops::Deref;
 use std::sync::{Arc, OnceLock};
 use thiserror::Error;
 use tokio::sync::watch;
@@ -92,8 +90,7 @@ pub struct WriteRequest<'a> {
 pub struct WriteBufferImpl<W, T, P> {
     catalog: Arc<Catalog>,
     segment_state: Arc<RwLock<SegmentState<T, W>>>,
-    parquet_cache: Arc<dyn ObjectStore>,
-    parquet_cache_metadata: Arc<RwLock<DatabaseTables>>,
+    parquet_cache: Arc<ParquetCache>,
     persister: Arc<P>,
     wal: Option<Arc<W>>,
     write_buffer_flusher: WriteBufferFlusher,
@@ -136,6 +133,9 @@ impl<W: Wal, T: TimeProvider, P: Persister> WriteBufferImpl<W, T, P> {
 
         let write_buffer_flusher = WriteBufferFlusher::new(Arc::clone(&segment_state));
 
+        let mem_pool: Arc<dyn MemoryPool> = Arc::new(UnboundedMemoryPool::default());
+        let parquet_cache = Arc::new(ParquetCache::new(&mem_pool, DEFAULT_MAX_SIZE_BYTES));
+
         let segment_state_persister = Arc::clone(&segment_state);
         let time_provider_persister = Arc::clone(&time_provider);
         let wal_perister = wal.clone();
@@ -157,10 +157,7 @@ impl<W: Wal, T: TimeProvider, P: Persister> WriteBufferImpl<W, T, P> {
         Ok(Self {
             catalog: loaded_state.catalog,
             segment_state,
-            parquet_cache: Arc::new(InMemory::new()),
-            parquet_cache_metadata: Arc::new(RwLock::new(DatabaseTables {
-                tables: HashMap::new(),
-            })),
+            parquet_cache,
             persister,
             wal,
             write_buffer_flusher,
@@ -286,13 +283,8 @@ impl<W: Wal, T: TimeProvider, P: Persister> WriteBufferImpl<W, T, P> {
 
         // Get any cached files and add them to the query
         let parquet_files = self
-            .parquet_cache_metadata
-            .read()
-            .deref()
-            .tables
-            .get(database_name)
-            .map(|table| table.parquet_files.clone())
-            .unwrap_or_default();
+            .parquet_cache
+            .get_parquet_files(database_name, table_name);
 
         // This is mostly the same as above, but we change the object store to
         // point to the in memory cache
@@ -321,7 +313,7 @@ impl<W: Wal, T: TimeProvider, P: Persister> WriteBufferImpl<W, T, P> {
                     e_tag: None,
                     version: None,
                 },
-                object_store: Arc::clone(&self.parquet_cache),
+                object_store: self.parquet_cache.object_store(),
             };
 
             let parquet_chunk = ParquetChunk {